@@ -1,119 +1,100 @@
-use serde::{Deserialize, Serialize};
-use reqwest;
-use std::env;
-use tracing::{debug, warn, error};
+use crate::llm::{self, Task, TokenSink};
 
-#[derive(Debug, Serialize, Deserialize)]
-struct OllamaOptions {
-    num_ctx: u32,
+async fn complete(task: Task, system: Option<&str>, prompt: String) -> Result<String, String> {
+    let request = llm::request_for(task, system.map(str::to_string), prompt);
+    llm::backend().complete(request).await
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct OllamaRequest {
-    model: String,
-    prompt: String,
-    stream: bool,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    options: Option<OllamaOptions>,
-}
-
-async fn post_to_ollama(request: OllamaRequest) -> Result<String, String> {
-    let endpoint = env::var("OLLAMA_ENDPOINT")
-        .unwrap_or_else(|_| "http://localhost:11434/api/generate".to_string());
-    
-    let client = reqwest::Client::builder()
-        .timeout(std::time::Duration::from_secs(60))
-        .build()
-        .map_err(|e| {
-            error!("Failed to create reqwest client: {}", e);
-            format!("Failed to create client: {}", e)
-        })?;
-
-    let mut last_error = String::new();
-    for attempt in 1..=3 {
-        debug!("Ollama request attempt {} to {}", attempt, endpoint);
-        match client.post(&endpoint)
-            .json(&request)
-            .send()
-            .await 
-        {
-            Ok(res) => {
-                let json: serde_json::Value = res.json().await.map_err(|e| {
-                    error!("Ollama JSON parse error: {}", e);
-                    format!("Invalid JSON response: {}", e)
-                })?;
-                debug!("Ollama request successful");
-                return Ok(json["response"].as_str().unwrap_or("No response").to_string());
-            }
-            Err(e) => {
-                warn!("Ollama request attempt {} failed: {}", attempt, e);
-                last_error = format!("Attempt {}: {}", attempt, e);
-                if attempt < 3 {
-                    tokio::time::sleep(std::time::Duration::from_millis(500 * attempt)).await;
-                }
-            }
-        }
-    }
-    error!("Ollama request failed after 3 attempts: {}", last_error);
-    Err(format!("AI Service unavailable after 3 attempts. Last error: {}", last_error))
+async fn stream(task: Task, system: Option<&str>, prompt: String, on_token: &mut TokenSink<'_>) -> Result<String, String> {
+    let request = llm::request_for(task, system.map(str::to_string), prompt);
+    llm::backend().stream(request, on_token).await
 }
 
 pub async fn generate_summary(text: &str) -> Result<String, String> {
-    post_to_ollama(OllamaRequest {
-        model: "llama3.1:latest".to_string(),
-        prompt: format!("Summarize this email concisely:\n\n{}", text),
-        stream: false,
-        options: None,
-    }).await
+    complete(Task::Summary, None, format!("Summarize this email concisely:\n\n{}", text)).await
 }
 
-pub async fn chat_with_emails(question: &str, emails_context: &str) -> Result<String, String> {
-    post_to_ollama(OllamaRequest {
-        model: "llama3.1:latest".to_string(),
-        prompt: format!("You are an AI assistant helping with an email inbox. Using the following emails context, answer the user's question.\n\nContext:\n{}\n\nQuestion: {}", emails_context, question),
-        stream: false,
-        options: Some(OllamaOptions { num_ctx: 8192 }), // Optimized context window
-    }).await
+/// Streams the answer through `on_token`; the whole answer is also returned.
+pub async fn chat_with_emails(question: &str, emails_context: &str, on_token: &mut TokenSink<'_>) -> Result<String, String> {
+    let system = "You are an AI assistant helping with an email inbox. Using the emails context given, answer the user's question.";
+    let prompt = format!("Context:\n{}\n\nQuestion: {}", emails_context, question);
+    stream(Task::Chat, Some(system), prompt, on_token).await
 }
 
-pub async fn generate_reply(email_text: &str) -> Result<String, String> {
-    post_to_ollama(OllamaRequest {
-        model: "llama3.1:latest".to_string(),
-        prompt: format!(
-            "You are an AI assistant tasked with writing a highly professional, concise reply to the following email. \
-            Do not include conversational filler like 'Here is your reply:' or 'Certainly!'. Draft only the final text \
-            of the response suitable for hitting send immediately.\n\nOriginal Email:\n{}",
-            email_text
-        ),
-        stream: false,
-        options: None,
-    }).await
+/// Streams the draft through `on_token`; the whole draft is also returned.
+pub async fn generate_reply(email_text: &str, on_token: &mut TokenSink<'_>) -> Result<String, String> {
+    let system = "You are an AI assistant tasked with writing a highly professional, concise reply to an email. \
+        Do not include conversational filler like 'Here is your reply:' or 'Certainly!'. Draft only the final text \
+        of the response suitable for hitting send immediately.";
+    stream(Task::Reply, Some(system), format!("Original Email:\n{}", email_text), on_token).await
 }
 
-pub async fn categorize_email(subject: &str, body: &str) -> Result<String, String> {
-    let result = post_to_ollama(OllamaRequest {
-        model: "llama3.1:latest".to_string(),
-        prompt: format!(
-            "Categorize the following email into exactly one of these labels: [Inbox, Work, Finance, Social, Promotions]. \
-            Respond with only the label name and nothing else. Output 'Inbox' if unsure.\n\nSubject: {}\n\nBody preview: {}",
-            subject,
-            if body.len() > 200 { &body[..200] } else { body }
-        ),
-        stream: false,
-        options: None,
-    }).await;
+/// Asks the model which of the user's `labels` apply to an email. Returns the
+/// matching label names as given (possibly none); anything else the model says is ignored.
+pub async fn categorize_email(subject: &str, body: &str, labels: &[String]) -> Result<Vec<String>, String> {
+    let prompt = format!(
+        "Choose which of these labels apply to the following email: [{}]. \
+        Respond with only the matching label names separated by commas, and nothing else. \
+        Output 'None' if no label clearly applies.\n\nSubject: {}\n\nBody preview: {}",
+        labels.join(", "),
+        subject,
+        body.chars().take(200).collect::<String>()
+    );
+    let response = complete(Task::Categorize, None, prompt).await?;
+
+    Ok(match_labels(&response, labels))
+}
 
-    match result {
-        Ok(response) => {
-            let response = response.trim();
-            let valid_labels = ["Inbox", "Work", "Finance", "Social", "Promotions"];
-            for label in valid_labels {
-                if response.to_lowercase().contains(&label.to_lowercase()) {
-                    return Ok(label.to_string());
-                }
+/// Picks the labels named in a model response, ignoring case, quotes and
+/// brackets; falls back to any label mentioned in free text.
+fn match_labels(response: &str, labels: &[String]) -> Vec<String> {
+    let mut matched: Vec<String> = Vec::new();
+    for item in response.split([',', '\n']) {
+        let item = item.trim().trim_matches(|c: char| matches!(c, '[' | ']' | '"' | '\'' | '.' | '*'));
+        if let Some(label) = labels.iter().find(|l| l.eq_ignore_ascii_case(item)) {
+            if !matched.contains(label) {
+                matched.push(label.clone());
             }
-            Ok("Inbox".to_string())
         }
-        Err(e) => Err(e),
+    }
+    if matched.is_empty() {
+        let response = response.to_lowercase();
+        matched = labels.iter().filter(|l| response.contains(&l.to_lowercase())).cloned().collect();
+    }
+    matched
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn chat_streams_the_whole_answer() {
+        llm::use_fake_backend();
+        let mut streamed = String::new();
+        let answer = chat_with_emails("When is the meeting?", "Subject: Meeting on Friday", &mut |piece: &str| {
+            streamed.push_str(piece)
+        })
+        .await
+        .unwrap();
+        assert_eq!(streamed, answer);
+        assert!(answer.ends_with("Last line: Question: When is the meeting?"));
+    }
+
+    #[tokio::test]
+    async fn categorize_keeps_only_known_labels() {
+        llm::use_fake_backend();
+        let labels = vec!["Invoice".to_string(), "Travel".to_string()];
+        // The fake model echoes the body preview, which names one label
+        let matched = categorize_email("March", "Your invoice for March is attached", &labels).await.unwrap();
+        assert_eq!(matched, vec!["Invoice".to_string()]);
+    }
+
+    #[test]
+    fn match_labels_reads_lists_and_free_text() {
+        let labels = vec!["Work".to_string(), "Family".to_string(), "Receipts".to_string()];
+        assert_eq!(match_labels("[\"work\", Receipts.]", &labels), vec!["Work", "Receipts"]);
+        assert_eq!(match_labels("This looks like a family email.", &labels), vec!["Family"]);
+        assert!(match_labels("None", &labels).is_empty());
     }
 }
@@ -1,49 +1,88 @@
 use serde::{Deserialize, Serialize};
-use rusqlite::{params, Connection, Result as SqlResult};
+use rusqlite::{params, Result as SqlResult};
+use argon2::Argon2;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use rand::RngExt;
+use std::sync::Mutex;
+use tracing::info;
+use crate::db;
+
+/// Key derived from the master password at unlock time. Credentials in the
+/// accounts table can only be read or written while it is present.
+static VAULT_KEY: Mutex<Option<[u8; 32]>> = Mutex::new(None);
+
+/// Marks values encrypted with XChaCha20-Poly1305; anything else is legacy plaintext.
+const ENCRYPTED_PREFIX: &str = "enc:v1:";
+
+const MIN_PASSWORD_LEN: usize = 12;
+
+/// Failed unlocks allowed before backoff starts, the first delay, and its cap.
+const FREE_ATTEMPTS: u32 = 3;
+const BASE_BACKOFF_SECS: i64 = 5;
+const MAX_BACKOFF_SECS: i64 = 15 * 60;
+
+/// Recovery keys avoid characters that are easy to misread on paper (0/O, 1/I).
+const RECOVERY_ALPHABET: &[u8; 32] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Account {
     pub email: String,
     pub imap_host: String,
     pub imap_port: u16,
+    /// Plaintext password when saving. Never populated by `get_accounts`;
+    /// use `get_password` to decrypt it on demand.
     pub password: Option<String>,
     pub is_demo: bool,
+    pub smtp_host: String,
+    pub smtp_port: u16,
+    /// Falls back to `email` when the SMTP server uses the same login as IMAP.
+    pub smtp_username: Option<String>,
+    /// Falls back to the IMAP password when unset. Like `password`, only used
+    /// when saving; read it back with `get_smtp_password`.
+    pub smtp_password: Option<String>,
 }
 
-pub fn init_db() -> SqlResult<()> {
-    let conn = Connection::open("neural-mail.db")?;
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS accounts (
-            email TEXT PRIMARY KEY,
-            imap_host TEXT NOT NULL,
-            imap_port INTEGER NOT NULL,
-            password TEXT,
-            is_demo INTEGER DEFAULT 0
-        )",
-        [],
-    )?;
-    Ok(())
-}
+pub fn save_account(account: &Account) -> Result<(), String> {
+    let password = account.password.as_deref().map(encrypt_secret).transpose()?;
+    let smtp_password = account.smtp_password.as_deref().map(encrypt_secret).transpose()?;
 
-pub fn save_account(account: &Account) -> SqlResult<()> {
-    let conn = Connection::open("neural-mail.db")?;
+    let conn = db::open().map_err(|e| e.to_string())?;
     conn.execute(
-        "INSERT OR REPLACE INTO accounts (email, imap_host, imap_port, password, is_demo) VALUES (?1, ?2, ?3, ?4, ?5)",
-        params![account.email, account.imap_host, account.imap_port, account.password, if account.is_demo { 1 } else { 0 }],
-    )?;
+        "INSERT OR REPLACE INTO accounts (email, imap_host, imap_port, password, is_demo, smtp_host, smtp_port, smtp_username, smtp_password)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        params![
+            account.email,
+            account.imap_host,
+            account.imap_port,
+            password,
+            if account.is_demo { 1 } else { 0 },
+            account.smtp_host,
+            account.smtp_port,
+            account.smtp_username,
+            smtp_password
+        ],
+    ).map_err(|e| e.to_string())?;
     Ok(())
 }
 
 pub fn get_accounts() -> SqlResult<Vec<Account>> {
-    let conn = Connection::open("neural-mail.db")?;
-    let mut stmt = conn.prepare("SELECT email, imap_host, imap_port, password, is_demo FROM accounts")?;
+    let conn = db::open()?;
+    let mut stmt = conn.prepare_cached(
+        "SELECT email, imap_host, imap_port, is_demo, smtp_host, smtp_port, smtp_username FROM accounts",
+    )?;
     let account_iter = stmt.query_map([], |row| {
         Ok(Account {
             email: row.get(0)?,
             imap_host: row.get(1)?,
             imap_port: row.get(2)?,
-            password: row.get(3)?,
-            is_demo: row.get::<_, i32>(4)? == 1,
+            password: None,
+            is_demo: row.get::<_, i32>(3)? == 1,
+            smtp_host: row.get(4)?,
+            smtp_port: row.get(5)?,
+            smtp_username: row.get(6)?,
+            smtp_password: None,
         })
     })?;
 
@@ -55,23 +94,400 @@ pub fn get_accounts() -> SqlResult<Vec<Account>> {
 }
 
 pub fn save_password(email: &str, password: &str) -> Result<(), String> {
-    let conn = Connection::open("neural-mail.db").map_err(|e| e.to_string())?;
+    let encrypted = encrypt_secret(password)?;
+    let conn = db::open().map_err(|e| e.to_string())?;
     conn.execute(
         "UPDATE accounts SET password = ?1 WHERE email = ?2",
-        params![password, email],
+        params![encrypted, email],
     ).map_err(|e| e.to_string())?;
     Ok(())
 }
 
 pub fn get_password(email: &str) -> Result<String, String> {
-    let conn = Connection::open("neural-mail.db").map_err(|e| e.to_string())?;
-    let mut stmt = conn.prepare("SELECT password FROM accounts WHERE email = ?1").map_err(|e| e.to_string())?;
+    read_secret(email, "password")?.ok_or_else(|| "No password found".to_string())
+}
+
+/// The dedicated SMTP password of `email`, if one was configured.
+pub fn get_smtp_password(email: &str) -> Result<Option<String>, String> {
+    read_secret(email, "smtp_password")
+}
+
+fn read_secret(email: &str, column: &str) -> Result<Option<String>, String> {
+    let conn = db::open().map_err(|e| e.to_string())?;
+    let mut stmt = conn
+        .prepare(&format!("SELECT {} FROM accounts WHERE email = ?1", column))
+        .map_err(|e| e.to_string())?;
     let mut rows = stmt.query(params![email]).map_err(|e| e.to_string())?;
     
     if let Some(row) = rows.next().map_err(|e| e.to_string())? {
-        let password: Option<String> = row.get(0).map_err(|e| e.to_string())?;
-        password.ok_or_else(|| "No password found".to_string())
+        let stored: Option<String> = row.get(0).map_err(|e| e.to_string())?;
+        stored.as_deref().map(decrypt_secret).transpose()
     } else {
         Err("Account not found".to_string())
     }
 }
+
+/// Derives the vault key from the master password with Argon2id and keeps it in
+/// memory. The per-database salt is created on first use. Any credentials still
+/// stored in plaintext are encrypted on the way.
+fn unlock(master_password: &str) -> Result<(), String> {
+    let salt = match db::get_kdf_salt().map_err(|e| e.to_string())? {
+        Some(salt) => BASE64.decode(salt).map_err(|e| format!("Corrupt key salt: {}", e))?,
+        None => {
+            let salt = random_bytes::<16>().to_vec();
+            db::set_kdf_salt(&BASE64.encode(&salt)).map_err(|e| e.to_string())?;
+            salt
+        }
+    };
+    let key = derive_key(master_password, &salt)?;
+    *VAULT_KEY.lock().unwrap() = Some(key);
+    encrypt_legacy_secrets()
+}
+
+/// Rejects master passwords that are short, use too few character classes or
+/// are built from obvious words.
+pub fn check_password_policy(password: &str) -> Result<(), String> {
+    if password.chars().count() < MIN_PASSWORD_LEN {
+        return Err(format!("Use at least {} characters.", MIN_PASSWORD_LEN));
+    }
+    let classes = [
+        password.chars().any(|c| c.is_lowercase()),
+        password.chars().any(|c| c.is_uppercase()),
+        password.chars().any(|c| c.is_ascii_digit()),
+        password.chars().any(|c| !c.is_alphanumeric()),
+    ];
+    if classes.iter().filter(|&&present| present).count() < 3 {
+        return Err("Mix at least three of: lowercase, uppercase, digits, symbols.".to_string());
+    }
+    let mut distinct: Vec<char> = password.chars().collect();
+    distinct.sort_unstable();
+    distinct.dedup();
+    if distinct.len() < 6 {
+        return Err("Avoid repeating the same few characters.".to_string());
+    }
+    let lowered = password.to_lowercase();
+    let common = ["password", "passw0rd", "123456", "qwerty", "letmein", "welcome", "neuralmail", "neural-mail"];
+    if common.iter().any(|word| lowered.contains(word)) {
+        return Err("Avoid common words and sequences like \"password\" or \"123456\".".to_string());
+    }
+    Ok(())
+}
+
+/// Sets the first master password, unlocks with it and returns the recovery key
+/// to show the user once.
+pub fn setup_master_password(password: &str) -> Result<String, String> {
+    check_password_policy(password)?;
+    // A new SQLCipher database is created with this password as its key
+    if !db::opens_without_key() {
+        db::set_key(password).map_err(|e| format!("Failed to open database: {}", e))?;
+    }
+    db::init_db().map_err(|e| e.to_string())?;
+    if db::get_master_password_hash().map_err(|e| e.to_string())?.is_some() {
+        return Err("A master password is already set".to_string());
+    }
+
+    let hash = bcrypt::hash(password, bcrypt::DEFAULT_COST).map_err(|e| e.to_string())?;
+    db::set_master_password(&hash).map_err(|e| e.to_string())?;
+    db::encrypt_in_place(password).map_err(|e| format!("Failed to encrypt database: {}", e))?;
+    unlock(password)?;
+    issue_recovery_key(password)
+}
+
+/// Checks `password` against the stored master password, subject to backoff
+/// after repeated failures, and unlocks on success.
+pub fn unlock_with_master_password(password: &str) -> Result<(), String> {
+    check_backoff()?;
+    // SQLCipher rejects a wrong key before the stored hash can even be read
+    let opened = db::opens_without_key() || db::set_key(password).is_ok();
+    if !opened {
+        record_failed_unlock()?;
+        return Err("Incorrect password".to_string());
+    }
+    // Encrypted databases can only be migrated once their key is known
+    db::init_db().map_err(|e| e.to_string())?;
+    if !matches_stored_hash(password)? {
+        record_failed_unlock()?;
+        return Err("Incorrect password".to_string());
+    }
+    db::set_unlock_value("failed_attempts", None).map_err(|e| e.to_string())?;
+    db::set_unlock_value("last_failed_at", None).map_err(|e| e.to_string())?;
+
+    // Plaintext databases from before SQLCipher are converted on first unlock
+    db::encrypt_in_place(password).map_err(|e| format!("Failed to encrypt database: {}", e))?;
+    unlock(password)
+}
+
+/// Replaces the master password while unlocked: the database key, the stored
+/// credentials and the password hash all move to `new_password`. Returns a new
+/// recovery key, since the old one only restores the old password.
+pub fn change_master_password(current_password: &str, new_password: &str) -> Result<String, String> {
+    current_key()?;
+    if !matches_stored_hash(current_password)? {
+        return Err("Current password is incorrect".to_string());
+    }
+    if new_password == current_password {
+        return Err("The new password must differ from the current one.".to_string());
+    }
+    check_password_policy(new_password)?;
+
+    db::rekey(new_password).map_err(|e| format!("Failed to re-key database: {}", e))?;
+    if let Err(e) = rekey(new_password) {
+        // Keep the database key in step with the hash and credentials
+        let _ = db::rekey(current_password);
+        return Err(e);
+    }
+    issue_recovery_key(new_password)
+}
+
+/// Uses a recovery key to unlock and set `new_password`. The recovery key is
+/// single use: a fresh one is returned for the new password.
+pub fn recover_with_key(recovery_key: &str, new_password: &str) -> Result<String, String> {
+    check_backoff()?;
+    check_password_policy(new_password)?;
+    let old_password = match open_recovery_blob(recovery_key) {
+        Ok(password) => password,
+        Err(e) => {
+            record_failed_unlock()?;
+            return Err(e);
+        }
+    };
+    unlock_with_master_password(&old_password)?;
+    change_master_password(&old_password, new_password)
+}
+
+fn matches_stored_hash(password: &str) -> Result<bool, String> {
+    match db::get_master_password_hash().map_err(|e| e.to_string())? {
+        Some(hash) => Ok(bcrypt::verify(password, &hash).unwrap_or(false)),
+        None => Err("No master password set".to_string()),
+    }
+}
+
+fn failed_unlocks() -> Result<(u32, i64), String> {
+    let count = db::get_unlock_value("failed_attempts").map_err(|e| e.to_string())?;
+    let last = db::get_unlock_value("last_failed_at").map_err(|e| e.to_string())?;
+    Ok((
+        count.and_then(|v| v.parse().ok()).unwrap_or(0),
+        last.and_then(|v| v.parse().ok()).unwrap_or(0),
+    ))
+}
+
+fn record_failed_unlock() -> Result<(), String> {
+    let (count, _) = failed_unlocks()?;
+    let now = chrono::Utc::now().timestamp();
+    db::set_unlock_value("failed_attempts", Some(&(count + 1).to_string())).map_err(|e| e.to_string())?;
+    db::set_unlock_value("last_failed_at", Some(&now.to_string())).map_err(|e| e.to_string())
+}
+
+/// Doubles the wait after every failure past `FREE_ATTEMPTS`. The counter is
+/// persisted, so restarting the app does not reset it.
+fn check_backoff() -> Result<(), String> {
+    let (count, last_failed_at) = failed_unlocks()?;
+    if count < FREE_ATTEMPTS {
+        return Ok(());
+    }
+    let doublings = (count - FREE_ATTEMPTS).min(16);
+    let backoff = (BASE_BACKOFF_SECS << doublings).min(MAX_BACKOFF_SECS);
+    let remaining = last_failed_at + backoff - chrono::Utc::now().timestamp();
+    if remaining > 0 {
+        return Err(format!("Too many failed attempts. Try again in {} seconds.", remaining));
+    }
+    Ok(())
+}
+
+/// Generates a recovery key and stores the master password encrypted under it,
+/// so the key can later stand in for a forgotten password. Anyone holding the
+/// key can therefore unlock; it is shown exactly once.
+fn issue_recovery_key(master_password: &str) -> Result<String, String> {
+    let raw = random_bytes::<20>();
+    let mut bits: u64 = 0;
+    let mut bit_count = 0;
+    let mut chars = String::with_capacity(32);
+    for byte in raw {
+        bits = (bits << 8) | byte as u64;
+        bit_count += 8;
+        while bit_count >= 5 {
+            bit_count -= 5;
+            chars.push(RECOVERY_ALPHABET[((bits >> bit_count) & 0x1f) as usize] as char);
+        }
+    }
+    let recovery_key = chars
+        .as_bytes()
+        .chunks(4)
+        .map(|group| std::str::from_utf8(group).unwrap())
+        .collect::<Vec<_>>()
+        .join("-");
+
+    let salt = random_bytes::<16>();
+    let wrapping_key = derive_key(&normalize_recovery_key(&recovery_key), &salt)?;
+    let blob = encrypt_with(&wrapping_key, master_password)?;
+    db::set_unlock_value("recovery_salt", Some(&BASE64.encode(salt))).map_err(|e| e.to_string())?;
+    db::set_unlock_value("recovery_blob", Some(&blob)).map_err(|e| e.to_string())?;
+    info!("Issued a new recovery key");
+    Ok(recovery_key)
+}
+
+fn open_recovery_blob(recovery_key: &str) -> Result<String, String> {
+    let salt = db::get_unlock_value("recovery_salt").map_err(|e| e.to_string())?;
+    let blob = db::get_unlock_value("recovery_blob").map_err(|e| e.to_string())?;
+    let (Some(salt), Some(blob)) = (salt, blob) else {
+        return Err("No recovery key has been set up".to_string());
+    };
+    let salt = BASE64.decode(salt).map_err(|e| format!("Corrupt recovery salt: {}", e))?;
+    let wrapping_key = derive_key(&normalize_recovery_key(recovery_key), &salt)?;
+    decrypt_with(&wrapping_key, &blob).map_err(|_| "That recovery key is not valid".to_string())
+}
+
+/// Ignores case, dashes and spaces so the printed groups can be typed loosely.
+fn normalize_recovery_key(recovery_key: &str) -> String {
+    recovery_key
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+/// Forgets the vault key (and the SQLCipher key, if enabled) so credentials
+/// and mail cannot be read again until the next `unlock`.
+pub fn lock() {
+    *VAULT_KEY.lock().unwrap() = None;
+    db::clear_key();
+    info!("Vault locked");
+}
+
+/// Re-encrypts every stored credential under a key derived from
+/// `new_master_password` with a fresh salt. Must be called while unlocked;
+/// the accounts, the new salt and the new password hash are written in one
+/// transaction.
+fn rekey(new_master_password: &str) -> Result<(), String> {
+    let old_key = current_key()?;
+    let new_salt = random_bytes::<16>();
+    let new_key = derive_key(new_master_password, &new_salt)?;
+    let new_hash = bcrypt::hash(new_master_password, bcrypt::DEFAULT_COST).map_err(|e| e.to_string())?;
+
+    let mut conn = db::open().map_err(|e| e.to_string())?;
+    let tx = conn.transaction().map_err(|e| e.to_string())?;
+    {
+        let mut stmt = tx
+            .prepare("SELECT email, password, smtp_password FROM accounts")
+            .map_err(|e| e.to_string())?;
+        let rows: Vec<(String, Option<String>, Option<String>)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .map_err(|e| e.to_string())?
+            .collect::<SqlResult<_>>()
+            .map_err(|e| e.to_string())?;
+
+        let reencrypt = |stored: Option<String>| -> Result<Option<String>, String> {
+            stored
+                .map(|s| decrypt_with(&old_key, &s).and_then(|plain| encrypt_with(&new_key, &plain)))
+                .transpose()
+        };
+        for (email, password, smtp_password) in rows {
+            tx.execute(
+                "UPDATE accounts SET password = ?1, smtp_password = ?2 WHERE email = ?3",
+                params![reencrypt(password)?, reencrypt(smtp_password)?, email],
+            ).map_err(|e| e.to_string())?;
+        }
+    }
+    tx.execute(
+        "INSERT INTO security (key, value) VALUES ('kdf_salt', ?1)
+         ON CONFLICT(key) DO UPDATE SET value=excluded.value",
+        params![BASE64.encode(new_salt)],
+    ).map_err(|e| e.to_string())?;
+    tx.execute(
+        "INSERT INTO security (key, value) VALUES ('master_password_hash', ?1)
+         ON CONFLICT(key) DO UPDATE SET value=excluded.value",
+        params![new_hash],
+    ).map_err(|e| e.to_string())?;
+    tx.commit().map_err(|e| e.to_string())?;
+
+    *VAULT_KEY.lock().unwrap() = Some(new_key);
+    info!("Re-encrypted stored credentials under the new master password");
+    Ok(())
+}
+
+fn derive_key(master_password: &str, salt: &[u8]) -> Result<[u8; 32], String> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(master_password.as_bytes(), salt, &mut key)
+        .map_err(|e| format!("Key derivation failed: {}", e))?;
+    Ok(key)
+}
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    rand::rng().fill(&mut bytes[..]);
+    bytes
+}
+
+fn current_key() -> Result<[u8; 32], String> {
+    let key = *VAULT_KEY.lock().unwrap();
+    key.ok_or_else(|| "Vault is locked".to_string())
+}
+
+fn encrypt_secret(plaintext: &str) -> Result<String, String> {
+    encrypt_with(&current_key()?, plaintext)
+}
+
+fn decrypt_secret(stored: &str) -> Result<String, String> {
+    // Rows written before encryption stay plaintext until the next unlock migrates them
+    if !stored.starts_with(ENCRYPTED_PREFIX) {
+        return Ok(stored.to_string());
+    }
+    decrypt_with(&current_key()?, stored)
+}
+
+fn encrypt_with(key: &[u8; 32], plaintext: &str) -> Result<String, String> {
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(key));
+    let nonce = random_bytes::<24>();
+    let ciphertext = cipher
+        .encrypt(XNonce::from_slice(&nonce), plaintext.as_bytes())
+        .map_err(|_| "Encryption failed".to_string())?;
+
+    let mut payload = nonce.to_vec();
+    payload.extend_from_slice(&ciphertext);
+    Ok(format!("{}{}", ENCRYPTED_PREFIX, BASE64.encode(payload)))
+}
+
+fn decrypt_with(key: &[u8; 32], stored: &str) -> Result<String, String> {
+    let Some(encoded) = stored.strip_prefix(ENCRYPTED_PREFIX) else {
+        return Ok(stored.to_string());
+    };
+    let payload = BASE64.decode(encoded).map_err(|e| format!("Corrupt credential: {}", e))?;
+    if payload.len() < 24 {
+        return Err("Corrupt credential: payload too short".to_string());
+    }
+    let (nonce, ciphertext) = payload.split_at(24);
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(key));
+    let plaintext = cipher
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| "Could not decrypt credential (wrong master password?)".to_string())?;
+    String::from_utf8(plaintext).map_err(|e| e.to_string())
+}
+
+/// Encrypts credentials written by versions that stored them in plaintext.
+fn encrypt_legacy_secrets() -> Result<(), String> {
+    let key = current_key()?;
+    let conn = db::open().map_err(|e| e.to_string())?;
+    for column in ["password", "smtp_password"] {
+        let mut stmt = conn
+            .prepare(&format!(
+                "SELECT email, {0} FROM accounts WHERE {0} IS NOT NULL AND {0} NOT LIKE '{1}%'",
+                column, ENCRYPTED_PREFIX
+            ))
+            .map_err(|e| e.to_string())?;
+        let legacy: Vec<(String, String)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(|e| e.to_string())?
+            .collect::<SqlResult<_>>()
+            .map_err(|e| e.to_string())?;
+
+        for (email, plaintext) in legacy {
+            conn.execute(
+                &format!("UPDATE accounts SET {} = ?1 WHERE email = ?2", column),
+                params![encrypt_with(&key, &plaintext)?, email],
+            ).map_err(|e| e.to_string())?;
+            info!("Encrypted stored {} for {}", column, email);
+        }
+    }
+    Ok(())
+}
@@ -1,5 +1,33 @@
-use rusqlite::{params, Connection, Result};
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::types::{Value, ValueRef};
+use rusqlite::{ffi, params, params_from_iter, Connection, Result};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
 use tracing::debug;
+use crate::migrations;
+use crate::search;
+
+/// Location of the active profile's database, chosen once at startup.
+static DB_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+type Pool = r2d2::Pool<SqliteConnectionManager>;
+pub type DbConnection = r2d2::PooledConnection<SqliteConnectionManager>;
+
+/// Shared connections to the database. Rebuilt lazily whenever the key changes.
+static POOL: Mutex<Option<Pool>> = Mutex::new(None);
+const POOL_SIZE: u32 = 4;
+const STATEMENT_CACHE_SIZE: usize = 64;
+
+/// Keys of the security table that `get_unlock_value`/`set_unlock_value` manage.
+#[cfg(feature = "sqlcipher")]
+const UNLOCK_STATE_KEYS: [&str; 4] = ["failed_attempts", "last_failed_at", "recovery_salt", "recovery_blob"];
+
+/// SQLCipher passphrase, set once the master password has been entered.
+/// SQLCipher stretches it with PBKDF2-HMAC-SHA512 and a per-file salt.
+#[cfg(feature = "sqlcipher")]
+static DB_KEY: Mutex<Option<String>> = Mutex::new(None);
 
 // We will redefine a basic struct to avoid fighting with Slint's generated Rc/Model types in DB threads
 #[derive(Debug, Clone)]
@@ -10,62 +38,479 @@ pub struct DbEmail {
     pub date: String,
     pub body: String,
     pub has_attachment: bool,
-    pub category: String,
+    /// Ids of the labels assigned to this email.
+    pub label_ids: Vec<i64>,
+    pub uid: Option<u32>,
+    pub is_read: bool,
+    /// Owning account email; empty for the generated demo inbox.
+    pub account: String,
+    /// To and Cc addresses as sent.
+    pub recipients: String,
+    /// Date header as UTC epoch seconds, if it could be parsed.
+    pub received_at: Option<i64>,
+    /// Message size in bytes (the raw RFC 822 message for synced mail).
+    pub size: i64,
+    /// Search match excerpt with hits wrapped in `SNIPPET_START`/`SNIPPET_END`;
+    /// only set by `list_headers` for text searches.
+    pub snippet: Option<String>,
 }
 
-pub fn init_db() -> Result<()> {
-    let conn = Connection::open("neural-mail.db")?;
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS emails (
-            id INTEGER PRIMARY KEY,
-            subject TEXT NOT NULL,
-            sender TEXT NOT NULL,
-            date_str TEXT NOT NULL,
-            body TEXT NOT NULL,
-            has_attachment INTEGER NOT NULL,
-            category TEXT NOT NULL DEFAULT 'Inbox'
-        )",
-        [],
-    )?;
-    // Migration: Add category if it doesn't exist (for existing DBs)
-    let _ = conn.execute(
-        "ALTER TABLE emails ADD COLUMN category TEXT NOT NULL DEFAULT 'Inbox'",
-        [],
-    );
+/// Control characters that mark search hits in `DbEmail::snippet`.
+pub const SNIPPET_START: char = '\u{1}';
+pub const SNIPPET_END: char = '\u{2}';
 
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS settings (
-            key TEXT PRIMARY KEY,
-            value TEXT NOT NULL
-        )",
-        [],
-    )?;
+/// Columns read by `email_from_row`, from `emails` aliased as `e`.
+const EMAIL_COLUMNS: &str = "e.id, e.subject, e.sender, e.date_str, e.body, e.has_attachment, (SELECT group_concat(label_id) FROM email_labels WHERE email_id = e.id), e.uid, e.is_read, e.account, e.recipients, e.received_at, e.size";
+/// `EMAIL_COLUMNS` with an empty body, for list pages.
+const EMAIL_HEADER_COLUMNS: &str = "e.id, e.subject, e.sender, e.date_str, '' AS body, e.has_attachment, (SELECT group_concat(label_id) FROM email_labels WHERE email_id = e.id), e.uid, e.is_read, e.account, e.recipients, e.received_at, e.size";
 
-    // Master Password Table
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS security (
-            key TEXT PRIMARY KEY,
-            value TEXT NOT NULL
-        )",
-        [],
-    )?;
+/// Orderings offered for the email list, stored in settings by name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortOrder {
+    /// Newest first; undated mail last.
+    #[default]
+    Date,
+    Sender,
+    Subject,
+    /// Largest first.
+    Size,
+}
+
+impl SortOrder {
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "sender" => SortOrder::Sender,
+            "subject" => SortOrder::Subject,
+            "size" => SortOrder::Size,
+            _ => SortOrder::Date,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SortOrder::Date => "date",
+            SortOrder::Sender => "sender",
+            SortOrder::Subject => "subject",
+            SortOrder::Size => "size",
+        }
+    }
+
+    /// Ends with the id so every row has a place of its own, which keyset
+    /// paging relies on.
+    fn keys(self) -> &'static [SortKey] {
+        match self {
+            SortOrder::Date => &[SortKey::desc("e.received_at"), SortKey::desc("e.id")],
+            SortOrder::Sender => &[
+                SortKey::asc("e.sender COLLATE NOCASE"),
+                SortKey::desc("e.received_at"),
+                SortKey::desc("e.id"),
+            ],
+            SortOrder::Subject => &[
+                SortKey::asc("e.subject COLLATE NOCASE"),
+                SortKey::desc("e.received_at"),
+                SortKey::desc("e.id"),
+            ],
+            SortOrder::Size => &[SortKey::desc("e.size"), SortKey::desc("e.received_at"), SortKey::desc("e.id")],
+        }
+    }
+
+    fn order_by(self) -> String {
+        order_by_clause(self.keys())
+    }
+}
+
+/// One expression a list is ordered by. SQLite puts NULLs first when
+/// ascending and last when descending.
+#[derive(Debug, Clone, Copy)]
+struct SortKey {
+    expr: &'static str,
+    descending: bool,
+}
+
+impl SortKey {
+    const fn asc(expr: &'static str) -> Self {
+        SortKey { expr, descending: false }
+    }
+
+    const fn desc(expr: &'static str) -> Self {
+        SortKey { expr, descending: true }
+    }
+}
+
+fn order_by_clause(keys: &[SortKey]) -> String {
+    keys.iter()
+        .map(|k| if k.descending { format!("{} DESC", k.expr) } else { k.expr.to_string() })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Search hits, best first.
+const RANK_KEYS: &[SortKey] = &[SortKey::asc("bm25(emails_fts, 10.0, 5.0, 2.0, 1.0)"), SortKey::desc("e.id")];
+
+/// A user-defined label; `color` is `#rrggbb`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DbLabel {
+    pub id: i64,
+    pub name: String,
+    pub color: String,
+}
+
+/// A named search query shown as a smart folder.
+#[derive(Debug, Clone)]
+pub struct DbSavedSearch {
+    pub id: i64,
+    pub name: String,
+    pub query: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct DbMailbox {
+    pub name: String,
+    pub delimiter: Option<String>,
+    pub special_use: Option<String>,
+    pub selectable: bool,
+}
+
+fn email_from_row(row: &rusqlite::Row) -> Result<DbEmail> {
+    Ok(DbEmail {
+        id: row.get(0)?,
+        subject: row.get(1)?,
+        sender: row.get(2)?,
+        date: row.get(3)?,
+        body: row.get(4)?,
+        has_attachment: row.get::<_, i32>(5)? == 1,
+        label_ids: row
+            .get::<_, Option<String>>(6)?
+            .map(|ids| ids.split(',').filter_map(|id| id.parse().ok()).collect())
+            .unwrap_or_default(),
+        uid: row.get(7)?,
+        is_read: row.get::<_, i32>(8)? == 1,
+        account: row.get(9)?,
+        recipients: row.get(10)?,
+        received_at: row.get(11)?,
+        size: row.get(12)?,
+        snippet: None,
+    })
+}
+
+/// Points every database function at `path`. Call once at startup, before the
+/// first `open`.
+pub fn set_database_path(path: PathBuf) {
+    if DB_PATH.set(path).is_err() {
+        debug!("Database path already set; ignoring");
+    }
+}
+
+pub fn database_path() -> &'static Path {
+    DB_PATH.get_or_init(|| PathBuf::from("neural-mail.db"))
+}
+
+/// A file next to the database, e.g. `neural-mail.db.unlock`.
+#[cfg(feature = "sqlcipher")]
+fn sidecar_path(suffix: &str) -> PathBuf {
+    let mut path = database_path().as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Security values that must be readable while locked. SQLCipher builds keep
+/// them in this file beside the database, since the security table cannot be
+/// read until the key is known.
+#[cfg(feature = "sqlcipher")]
+fn unlock_state_path() -> PathBuf {
+    sidecar_path(".unlock")
+}
+
+fn pool_error(e: r2d2::Error) -> rusqlite::Error {
+    rusqlite::Error::SqliteFailure(ffi::Error::new(ffi::SQLITE_CANTOPEN), Some(e.to_string()))
+}
+
+fn build_pool() -> Result<Pool> {
+    #[cfg(feature = "sqlcipher")]
+    let key = DB_KEY.lock().unwrap().clone();
+    let manager = SqliteConnectionManager::file(database_path()).with_init(move |conn| {
+        #[cfg(feature = "sqlcipher")]
+        if let Some(key) = key.as_deref() {
+            conn.pragma_update(None, "key", key)?;
+        }
+        conn.busy_timeout(Duration::from_secs(5))?;
+        // WAL lets the UI read while a sync is writing
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        // Label assignments go away with their email or label
+        conn.pragma_update(None, "foreign_keys", true)?;
+        conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_SIZE);
+        Ok(())
+    });
+    r2d2::Pool::builder()
+        .max_size(POOL_SIZE)
+        .min_idle(Some(0))
+        .connection_timeout(Duration::from_secs(10))
+        .build(manager)
+        .map_err(pool_error)
+}
+
+/// Closes idle pooled connections so the next `open` reconnects with the
+/// current key. Connections still checked out finish what they are doing.
+fn reset_pool() {
+    *POOL.lock().unwrap() = None;
+}
+
+/// Checks out a connection to the mail database from the shared pool, keyed
+/// when built with the `sqlcipher` feature.
+pub fn open() -> Result<DbConnection> {
+    // Fail fast instead of waiting out the pool timeout on a locked database
+    #[cfg(feature = "sqlcipher")]
+    if DB_KEY.lock().unwrap().is_none() && database_state() == DbState::Encrypted {
+        return Err(rusqlite::Error::SqliteFailure(
+            ffi::Error::new(ffi::SQLITE_AUTH),
+            Some("The database is locked".to_string()),
+        ));
+    }
+    let pool = {
+        let mut guard = POOL.lock().unwrap();
+        match guard.as_ref() {
+            Some(pool) => pool.clone(),
+            None => {
+                let pool = build_pool()?;
+                *guard = Some(pool.clone());
+                pool
+            }
+        }
+    };
+    pool.get().map_err(pool_error)
+}
+
+/// Runs blocking database work on Tokio's blocking threads, so callers on the
+/// UI thread can await results instead of stalling rendering.
+pub async fn call<T, F>(f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f).await.map_err(|e| {
+        rusqlite::Error::SqliteFailure(ffi::Error::new(ffi::SQLITE_ABORT), Some(e.to_string()))
+    })?
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DbState {
+    Missing,
+    Plaintext,
+    Encrypted,
+}
+
+/// Classifies the database file by its header; SQLCipher files have no
+/// readable "SQLite format 3" magic.
+pub fn database_state() -> DbState {
+    let mut header = [0u8; 16];
+    match std::fs::File::open(database_path()).and_then(|mut f| f.read_exact(&mut header)) {
+        Ok(()) if &header == b"SQLite format 3\0" => DbState::Plaintext,
+        Ok(()) => DbState::Encrypted,
+        // Absent or still empty: SQLite has not written a page yet
+        Err(_) => DbState::Missing,
+    }
+}
+
+/// Whether the database can be read before the master password is known.
+/// With SQLCipher a new database waits for the key so it is created encrypted.
+pub fn opens_without_key() -> bool {
+    match database_state() {
+        DbState::Plaintext => true,
+        DbState::Missing => !cfg!(feature = "sqlcipher"),
+        DbState::Encrypted => false,
+    }
+}
+
+/// Supplies the master password as the SQLCipher key. For an existing encrypted
+/// database the key is checked by reading the schema; a wrong key is rejected.
+#[cfg(feature = "sqlcipher")]
+pub fn set_key(passphrase: &str) -> Result<()> {
+    if database_state() == DbState::Encrypted {
+        // Checked on a private connection so a wrong key never reaches the pool
+        let conn = Connection::open(database_path())?;
+        conn.pragma_update(None, "key", passphrase)?;
+        conn.query_row("SELECT count(*) FROM sqlite_master", [], |row| row.get::<_, i64>(0))?;
+    }
+    *DB_KEY.lock().unwrap() = Some(passphrase.to_string());
+    reset_pool();
+    Ok(())
+}
+
+#[cfg(not(feature = "sqlcipher"))]
+pub fn set_key(_passphrase: &str) -> Result<()> {
+    Ok(())
+}
 
+/// Drops the SQLCipher key; every `open` fails to read until `set_key` again.
+pub fn clear_key() {
+    #[cfg(feature = "sqlcipher")]
+    {
+        *DB_KEY.lock().unwrap() = None;
+        reset_pool();
+    }
+}
+
+/// Converts a plaintext database to SQLCipher in place: the contents are
+/// exported into an encrypted copy which then replaces the original file.
+/// Does nothing if the database is already encrypted.
+#[cfg(feature = "sqlcipher")]
+pub fn encrypt_in_place(passphrase: &str) -> Result<()> {
+    if database_state() != DbState::Plaintext {
+        return set_key(passphrase);
+    }
+    let encrypted_path = sidecar_path(".encrypting");
+    let _ = std::fs::remove_file(&encrypted_path);
+    reset_pool();
+    {
+        let conn = Connection::open(database_path())?;
+        // Fold the WAL back in; a leftover -wal file would be replayed onto the
+        // encrypted copy once it takes the original's name
+        let mode: String = conn.pragma_update_and_check(None, "journal_mode", "DELETE", |row| row.get(0))?;
+        if !mode.eq_ignore_ascii_case("delete") {
+            return Err(rusqlite::Error::SqliteFailure(
+                ffi::Error::new(ffi::SQLITE_BUSY),
+                Some("The database is in use; close other connections and try again".to_string()),
+            ));
+        }
+        // Values that must stay readable while locked move out to the sidecar file
+        for key in UNLOCK_STATE_KEYS {
+            if let Some(value) = security_value(&conn, key)? {
+                set_unlock_value(key, Some(&value))?;
+            }
+        }
+        let user_version: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        conn.execute("ATTACH DATABASE ?1 AS encrypted KEY ?2", params![encrypted_path.to_string_lossy(), passphrase])?;
+        // sqlcipher_export copies schema and data but not the user_version header field
+        conn.query_row("SELECT sqlcipher_export('encrypted')", [], |_| Ok(()))?;
+        conn.execute_batch(&format!("PRAGMA encrypted.user_version = {}", user_version))?;
+        conn.execute("DETACH DATABASE encrypted", [])?;
+    }
+    restrict_permissions(&encrypted_path)?;
+    std::fs::rename(&encrypted_path, database_path())
+        .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+    set_key(passphrase)
+}
+
+#[cfg(not(feature = "sqlcipher"))]
+pub fn encrypt_in_place(_passphrase: &str) -> Result<()> {
+    Ok(())
+}
+
+fn restrict_permissions(path: &Path) -> Result<()> {
     #[cfg(unix)]
     {
         use std::os::unix::fs::PermissionsExt;
-        let mut perms = std::fs::metadata("neural-mail.db")
+        let mut perms = std::fs::metadata(path)
             .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?
             .permissions();
         perms.set_mode(0o600);
-        std::fs::set_permissions("neural-mail.db", perms)
+        std::fs::set_permissions(path, perms)
             .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
     }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// Brings the schema up to date through the versioned migrations. Fails if the
+/// database was written by a newer build.
+pub fn init_db() -> Result<()> {
+    let mut conn = open()?;
+    migrations::run(&mut conn)?;
+    restrict_permissions(database_path())
+}
+
+fn security_value(conn: &Connection, key: &str) -> Result<Option<String>> {
+    let mut stmt = conn.prepare_cached("SELECT value FROM security WHERE key = ?1")?;
+    let mut rows = stmt.query(params![key])?;
+    if let Some(row) = rows.next()? {
+        Ok(Some(row.get(0)?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Reads a security value that is needed before unlock, such as the failed
+/// attempt counter or the recovery blob.
+#[cfg(not(feature = "sqlcipher"))]
+pub fn get_unlock_value(key: &str) -> Result<Option<String>> {
+    let conn = open()?;
+    // The table may not exist yet on a fresh install
+    match security_value(&conn, key) {
+        Err(rusqlite::Error::SqliteFailure(_, Some(msg))) if msg.starts_with("no such table") => Ok(None),
+        other => other,
+    }
+}
+
+/// Stores (or with `None`, removes) a security value that is needed before unlock.
+#[cfg(not(feature = "sqlcipher"))]
+pub fn set_unlock_value(key: &str, value: Option<&str>) -> Result<()> {
+    let conn = open()?;
+    match value {
+        Some(value) => conn.execute(
+            "INSERT INTO security (key, value) VALUES (?1, ?2)
+             ON CONFLICT(key) DO UPDATE SET value=excluded.value",
+            params![key, value],
+        )?,
+        None => conn.execute("DELETE FROM security WHERE key = ?1", params![key])?,
+    };
+    Ok(())
+}
+
+#[cfg(feature = "sqlcipher")]
+fn read_unlock_state() -> Result<Vec<(String, String)>> {
+    match std::fs::read_to_string(unlock_state_path()) {
+        Ok(contents) => Ok(contents
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(rusqlite::Error::ToSqlConversionFailure(Box::new(e))),
+    }
+}
+
+#[cfg(feature = "sqlcipher")]
+pub fn get_unlock_value(key: &str) -> Result<Option<String>> {
+    Ok(read_unlock_state()?.into_iter().find(|(k, _)| k == key).map(|(_, v)| v))
+}
+
+#[cfg(feature = "sqlcipher")]
+pub fn set_unlock_value(key: &str, value: Option<&str>) -> Result<()> {
+    let mut state = read_unlock_state()?;
+    state.retain(|(k, _)| k != key);
+    if let Some(value) = value {
+        state.push((key.to_string(), value.to_string()));
+    }
+    let contents: String = state.iter().map(|(k, v)| format!("{}={}\n", k, v)).collect();
 
+    // Write-then-rename so a crash never leaves a truncated counter behind
+    let tmp_path = sidecar_path(".unlock.tmp");
+    std::fs::write(&tmp_path, contents).map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+    restrict_permissions(&tmp_path)?;
+    std::fs::rename(&tmp_path, unlock_state_path()).map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+}
+
+/// Changes the SQLCipher key of the open database to `passphrase`.
+#[cfg(feature = "sqlcipher")]
+pub fn rekey(passphrase: &str) -> Result<()> {
+    let conn = open()?;
+    conn.pragma_update(None, "rekey", passphrase)?;
+    drop(conn);
+    *DB_KEY.lock().unwrap() = Some(passphrase.to_string());
+    // Other pooled connections still hold the old key
+    reset_pool();
+    Ok(())
+}
+
+#[cfg(not(feature = "sqlcipher"))]
+pub fn rekey(_passphrase: &str) -> Result<()> {
     Ok(())
 }
 
 pub fn set_master_password(hash: &str) -> Result<()> {
-    let conn = Connection::open("neural-mail.db")?;
+    let conn = open()?;
     conn.execute(
         "INSERT INTO security (key, value) VALUES ('master_password_hash', ?1)
          ON CONFLICT(key) DO UPDATE SET value=excluded.value",
@@ -75,8 +520,8 @@ pub fn set_master_password(hash: &str) -> Result<()> {
 }
 
 pub fn get_master_password_hash() -> Result<Option<String>> {
-    let conn = Connection::open("neural-mail.db")?;
-    let mut stmt = conn.prepare("SELECT value FROM security WHERE key = 'master_password_hash'")?;
+    let conn = open()?;
+    let mut stmt = conn.prepare_cached("SELECT value FROM security WHERE key = 'master_password_hash'")?;
     let mut rows = stmt.query([])?;
     if let Some(row) = rows.next()? {
         Ok(Some(row.get(0)?))
@@ -85,17 +530,36 @@ pub fn get_master_password_hash() -> Result<Option<String>> {
     }
 }
 
+/// Salt for deriving the credential encryption key from the master password (base64).
+pub fn get_kdf_salt() -> Result<Option<String>> {
+    let conn = open()?;
+    let mut stmt = conn.prepare_cached("SELECT value FROM security WHERE key = 'kdf_salt'")?;
+    let mut rows = stmt.query([])?;
+    if let Some(row) = rows.next()? {
+        Ok(Some(row.get(0)?))
+    } else {
+        Ok(None)
+    }
+}
+
+pub fn set_kdf_salt(salt: &str) -> Result<()> {
+    let conn = open()?;
+    conn.execute(
+        "INSERT INTO security (key, value) VALUES ('kdf_salt', ?1)
+         ON CONFLICT(key) DO UPDATE SET value=excluded.value",
+        params![salt],
+    )?;
+    Ok(())
+}
+
 pub fn insert_emails(emails: &[DbEmail]) -> Result<()> {
-    let mut conn = Connection::open("neural-mail.db")?;
+    let mut conn = open()?;
     let tx = conn.transaction()?;
 
-    // Clear existing to avoid duplicates on every run
-    tx.execute("DELETE FROM emails", [])?;
-
     {
-        let mut stmt = tx.prepare(
-            "INSERT INTO emails (id, subject, sender, date_str, body, has_attachment, category) 
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        let mut stmt = tx.prepare_cached(
+            "INSERT INTO emails (id, subject, sender, date_str, body, has_attachment, uid, is_read, account, recipients, received_at, size) 
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
         )?;
 
         for email in emails {
@@ -106,7 +570,12 @@ pub fn insert_emails(emails: &[DbEmail]) -> Result<()> {
                 email.date,
                 email.body,
                 if email.has_attachment { 1 } else { 0 },
-                email.category
+                email.uid,
+                if email.is_read { 1 } else { 0 },
+                email.account,
+                email.recipients,
+                email.received_at,
+                email.size
             ])?;
         }
     }
@@ -114,23 +583,331 @@ pub fn insert_emails(emails: &[DbEmail]) -> Result<()> {
     Ok(())
 }
 
-pub fn get_all_emails() -> Result<Vec<DbEmail>> {
-    let conn = Connection::open("neural-mail.db")?;
-    let mut stmt = conn.prepare(
-        "SELECT id, subject, sender, date_str, body, has_attachment, category FROM emails",
+/// Inserts synced messages keyed on (account, mailbox, uid). Existing rows keep
+/// their local state (labels) and only have their server flags refreshed.
+pub fn upsert_emails(account: &str, mailbox: &str, emails: &[DbEmail]) -> Result<()> {
+    let mut conn = open()?;
+    let tx = conn.transaction()?;
+
+    {
+        let mut stmt = tx.prepare_cached(
+            "INSERT INTO emails (account, mailbox, uid, subject, sender, date_str, body, has_attachment, is_read, recipients, received_at, size)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)
+             ON CONFLICT(account, mailbox, uid) DO UPDATE SET is_read = excluded.is_read",
+        )?;
+
+        for email in emails {
+            stmt.execute(params![
+                account,
+                mailbox,
+                email.uid,
+                email.subject,
+                email.sender,
+                email.date,
+                email.body,
+                if email.has_attachment { 1 } else { 0 },
+                if email.is_read { 1 } else { 0 },
+                email.recipients,
+                email.received_at,
+                email.size
+            ])?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Applies server-side flag changes and drops rows whose UID is no longer on the server.
+pub fn reconcile_mailbox(account: &str, mailbox: &str, flags: &[(u32, bool)]) -> Result<usize> {
+    let mut conn = open()?;
+    let tx = conn.transaction()?;
+
+    let known: Vec<u32> = {
+        let mut stmt = tx.prepare_cached("SELECT uid FROM emails WHERE account = ?1 AND mailbox = ?2 AND uid IS NOT NULL")?;
+        let rows = stmt.query_map(params![account, mailbox], |row| row.get(0))?;
+        rows.collect::<Result<_>>()?
+    };
+    let on_server: std::collections::HashSet<u32> = flags.iter().map(|(uid, _)| *uid).collect();
+
+    let mut expunged = 0;
+    {
+        let mut update = tx.prepare_cached("UPDATE emails SET is_read = ?1 WHERE account = ?2 AND mailbox = ?3 AND uid = ?4")?;
+        for (uid, is_read) in flags {
+            update.execute(params![if *is_read { 1 } else { 0 }, account, mailbox, uid])?;
+        }
+
+        let mut delete = tx.prepare_cached("DELETE FROM emails WHERE account = ?1 AND mailbox = ?2 AND uid = ?3")?;
+        for uid in known.iter().filter(|uid| !on_server.contains(uid)) {
+            expunged += delete.execute(params![account, mailbox, uid])?;
+        }
+    }
+    tx.commit()?;
+    Ok(expunged)
+}
+
+pub fn clear_mailbox(account: &str, mailbox: &str) -> Result<()> {
+    let conn = open()?;
+    conn.execute(
+        "DELETE FROM emails WHERE account = ?1 AND mailbox = ?2 AND uid IS NOT NULL",
+        params![account, mailbox],
     )?;
-    let email_iter = stmt.query_map([], |row| {
-        Ok(DbEmail {
-            id: row.get(0)?,
-            subject: row.get(1)?,
-            sender: row.get(2)?,
-            date: row.get(3)?,
-            body: row.get(4)?,
-            has_attachment: row.get::<_, i32>(5)? == 1,
-            category: row.get(6)?,
-        })
+    Ok(())
+}
+
+/// Removes the generated demo inbox (rows that never came from IMAP).
+pub fn clear_mock_emails() -> Result<()> {
+    let conn = open()?;
+    conn.execute("DELETE FROM emails WHERE uid IS NULL", [])?;
+    Ok(())
+}
+
+/// Returns `(uid_validity, highest_uid)` recorded by the last sync of `mailbox`.
+pub fn get_sync_state(account: &str, mailbox: &str) -> Result<Option<(u32, u32)>> {
+    let conn = open()?;
+    let mut stmt = conn.prepare_cached(
+        "SELECT uid_validity, highest_uid FROM sync_state WHERE account = ?1 AND mailbox = ?2",
+    )?;
+    let mut rows = stmt.query(params![account, mailbox])?;
+    if let Some(row) = rows.next()? {
+        Ok(Some((row.get(0)?, row.get(1)?)))
+    } else {
+        Ok(None)
+    }
+}
+
+pub fn set_sync_state(account: &str, mailbox: &str, uid_validity: u32, highest_uid: u32) -> Result<()> {
+    let conn = open()?;
+    conn.execute(
+        "INSERT INTO sync_state (account, mailbox, uid_validity, highest_uid) VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT(account, mailbox) DO UPDATE SET uid_validity=excluded.uid_validity, highest_uid=excluded.highest_uid",
+        params![account, mailbox, uid_validity, highest_uid],
+    )?;
+    Ok(())
+}
+
+/// What the email list shows. Lists are read a page at a time with
+/// `count_list` and `list_headers`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EmailList {
+    /// The INBOX of an account, or of all accounts (the unified inbox) when empty.
+    Inbox { account: String },
+    Label { account: String, label_id: i64 },
+    Mailbox { account: String, mailbox: String },
+    /// Full-text search with Gmail-style operators (see `search::parse`).
+    Search { account: String, query: String },
+}
+
+/// Where a page of a list starts: at a row offset, or right after a row whose
+/// sort key is known, which SQLite finds without stepping over the rows before it.
+#[derive(Debug, Clone)]
+pub enum PageStart {
+    Offset(usize),
+    After(ListCursor),
+}
+
+/// The sort key of a row of a list, for `PageStart::After`.
+#[derive(Debug, Clone)]
+pub struct ListCursor(Vec<Value>);
+
+/// A page of `list_headers` and the cursor of its last row.
+pub struct ListPage {
+    pub emails: Vec<DbEmail>,
+    pub last: Option<ListCursor>,
+}
+
+/// The FROM/WHERE part of a list query, its parameters, ordering and snippet column.
+struct ListQuery {
+    source: String,
+    values: Vec<Value>,
+    keys: &'static [SortKey],
+    snippet: &'static str,
+}
+
+impl ListQuery {
+    /// Restricts the query to the rows ordered after `cursor`: those equal to
+    /// it on some leading keys and past it on the next one.
+    fn after(&mut self, cursor: &ListCursor) {
+        let mut alternatives = Vec::new();
+        for (i, (key, value)) in self.keys.iter().zip(&cursor.0).enumerate() {
+            let past = match (value, key.descending) {
+                // NULLs come last when descending
+                (Value::Null, true) => continue,
+                (Value::Null, false) => format!("{} IS NOT NULL", key.expr),
+                (_, true) => format!("({0} < ? OR {0} IS NULL)", key.expr),
+                (_, false) => format!("{} > ?", key.expr),
+            };
+            let mut terms = Vec::new();
+            for (earlier, earlier_value) in self.keys.iter().zip(&cursor.0).take(i) {
+                terms.push(format!("{} IS ?", earlier.expr));
+                self.values.push(earlier_value.clone());
+            }
+            if !matches!(value, Value::Null) {
+                self.values.push(value.clone());
+            }
+            terms.push(past);
+            alternatives.push(terms.join(" AND "));
+        }
+        if alternatives.is_empty() {
+            alternatives.push("0".to_string());
+        }
+        self.source = format!("{} AND ({})", self.source, alternatives.join(" OR "));
+    }
+}
+
+impl EmailList {
+    /// Text matches are ranked with bm25, weighting subject over sender over
+    /// body; everything else (including filter-only searches) follows `sort`.
+    fn query(&self, sort: SortOrder) -> ListQuery {
+        let plain = |filter: &str, values: Vec<Value>| ListQuery {
+            source: format!("FROM emails e WHERE {}", filter),
+            values,
+            keys: sort.keys(),
+            snippet: "NULL",
+        };
+        match self {
+            EmailList::Inbox { account } => plain(
+                "e.mailbox = 'INBOX' AND (?1 = '' OR e.account = ?1)",
+                vec![Value::Text(account.clone())],
+            ),
+            EmailList::Label { account, label_id } => plain(
+                "e.id IN (SELECT email_id FROM email_labels WHERE label_id = ?1) AND (?2 = '' OR e.account = ?2)",
+                vec![Value::Integer(*label_id), Value::Text(account.clone())],
+            ),
+            EmailList::Mailbox { account, mailbox } => plain(
+                "e.account = ?1 AND e.mailbox = ?2",
+                vec![Value::Text(account.clone()), Value::Text(mailbox.clone())],
+            ),
+            EmailList::Search { account, query } => {
+                let parsed = search::parse(query);
+                let mut filters = vec!["(? = '' OR e.account = ?)".to_string()];
+                let mut values = vec![Value::Text(account.clone()), Value::Text(account.clone())];
+                if parsed.has_attachment {
+                    filters.push("e.has_attachment = 1".to_string());
+                }
+                if let Some(read) = parsed.read {
+                    filters.push(format!("e.is_read = {}", if read { 1 } else { 0 }));
+                }
+                if let Some(label) = parsed.label.clone() {
+                    filters.push(
+                        "e.id IN (SELECT el.email_id FROM email_labels el JOIN labels l ON l.id = el.label_id WHERE l.name = ?)"
+                            .to_string(),
+                    );
+                    values.push(Value::Text(label));
+                }
+                if let Some(before) = parsed.before {
+                    filters.push("e.received_at < ?".to_string());
+                    values.push(Value::Integer(before));
+                }
+                if let Some(after) = parsed.after {
+                    filters.push("e.received_at >= ?".to_string());
+                    values.push(Value::Integer(after));
+                }
+                if let Some(excluded) = parsed.exclude_expression() {
+                    filters.push("e.id NOT IN (SELECT rowid FROM emails_fts WHERE emails_fts MATCH ?)".to_string());
+                    values.push(Value::Text(excluded));
+                }
+
+                match parsed.match_expression() {
+                    Some(expression) => {
+                        values.insert(0, Value::Text(expression));
+                        ListQuery {
+                            source: format!(
+                                "FROM emails_fts JOIN emails e ON e.id = emails_fts.rowid
+                                 WHERE emails_fts MATCH ? AND {}",
+                                filters.join(" AND ")
+                            ),
+                            values,
+                            keys: RANK_KEYS,
+                            snippet: "snippet(emails_fts, -1, char(1), char(2), '…', 12)",
+                        }
+                    }
+                    None => plain(&filters.join(" AND "), values),
+                }
+            }
+        }
+    }
+}
+
+/// Number of emails in `list`.
+pub fn count_list(list: &EmailList) -> Result<usize> {
+    let query = list.query(SortOrder::default());
+    let conn = open()?;
+    let mut stmt = conn.prepare_cached(&format!("SELECT COUNT(*) {}", query.source))?;
+    let count: i64 = stmt.query_row(params_from_iter(query.values), |row| row.get(0))?;
+    Ok(count as usize)
+}
+
+/// Number of unread emails in `list`.
+pub fn count_unread(list: &EmailList) -> Result<usize> {
+    let query = list.query(SortOrder::default());
+    let conn = open()?;
+    let mut stmt = conn.prepare_cached(&format!("SELECT COUNT(*) {} AND e.is_read = 0", query.source))?;
+    let count: i64 = stmt.query_row(params_from_iter(query.values), |row| row.get(0))?;
+    Ok(count as usize)
+}
+
+/// One page of `list` without message bodies (`body` is left empty; see
+/// `get_email_body`). Search results carry their match snippet.
+pub fn list_headers(list: &EmailList, sort: SortOrder, start: &PageStart, limit: usize) -> Result<ListPage> {
+    let mut query = list.query(sort);
+    let offset = match start {
+        PageStart::Offset(offset) => *offset,
+        PageStart::After(cursor) => {
+            query.after(cursor);
+            0
+        }
+    };
+    query.values.push(Value::Integer(limit as i64));
+    query.values.push(Value::Integer(offset as i64));
+
+    let key_columns: Vec<&str> = query.keys.iter().map(|k| k.expr).collect();
+    let conn = open()?;
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT {}, {}, {} {} ORDER BY {} LIMIT ? OFFSET ?",
+        EMAIL_HEADER_COLUMNS,
+        query.snippet,
+        key_columns.join(", "),
+        query.source,
+        order_by_clause(query.keys)
+    ))?;
+    let key_count = key_columns.len();
+    let row_iter = stmt.query_map(params_from_iter(query.values), |row| {
+        let mut email = email_from_row(row)?;
+        email.snippet = row.get(13)?;
+        let key = (0..key_count).map(|i| row.get(14 + i)).collect::<Result<Vec<Value>>>()?;
+        Ok((email, key))
     })?;
 
+    let mut emails = Vec::with_capacity(limit);
+    let mut last = None;
+    for row in row_iter {
+        let (email, key) = row?;
+        emails.push(email);
+        last = Some(ListCursor(key));
+    }
+    Ok(ListPage { emails, last })
+}
+
+pub fn get_email_body(id: i32) -> Result<Option<String>> {
+    let conn = open()?;
+    let mut stmt = conn.prepare_cached("SELECT body FROM emails WHERE id = ?1")?;
+    let mut rows = stmt.query(params![id])?;
+    match rows.next()? {
+        Some(row) => Ok(Some(row.get(0)?)),
+        None => Ok(None),
+    }
+}
+
+/// The newest `limit` emails across all accounts, with bodies.
+pub fn get_recent_emails(limit: usize) -> Result<Vec<DbEmail>> {
+    let conn = open()?;
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT {} FROM emails e ORDER BY {} LIMIT ?1",
+        EMAIL_COLUMNS,
+        SortOrder::Date.order_by()
+    ))?;
+    let email_iter = stmt.query_map(params![limit as i64], email_from_row)?;
+
     let mut emails = Vec::new();
     for email in email_iter {
         emails.push(email?);
@@ -138,27 +915,15 @@ pub fn get_all_emails() -> Result<Vec<DbEmail>> {
     Ok(emails)
 }
 
-pub fn search_emails(query: &str) -> Result<Vec<DbEmail>> {
-    if query.trim().is_empty() {
-        return get_all_emails();
-    }
-    let conn = Connection::open("neural-mail.db")?;
-    let mut stmt = conn.prepare(
-        "SELECT id, subject, sender, date_str, body, has_attachment, category FROM emails 
-         WHERE subject LIKE ?1 OR sender LIKE ?2 OR body LIKE ?3",
-    )?;
-    let q = format!("%{}%", query);
-    let email_iter = stmt.query_map(params![&q, &q, &q], |row| {
-        Ok(DbEmail {
-            id: row.get(0)?,
-            subject: row.get(1)?,
-            sender: row.get(2)?,
-            date: row.get(3)?,
-            body: row.get(4)?,
-            has_attachment: row.get::<_, i32>(5)? == 1,
-            category: row.get(6)?,
-        })
-    })?;
+/// The newest `limit` emails the categorizer has not looked at yet, with bodies.
+pub fn get_uncategorized_emails(limit: usize) -> Result<Vec<DbEmail>> {
+    let conn = open()?;
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT {} FROM emails e WHERE e.categorized = 0 ORDER BY {} LIMIT ?1",
+        EMAIL_COLUMNS,
+        SortOrder::Date.order_by()
+    ))?;
+    let email_iter = stmt.query_map(params![limit as i64], email_from_row)?;
 
     let mut emails = Vec::new();
     for email in email_iter {
@@ -167,43 +932,245 @@ pub fn search_emails(query: &str) -> Result<Vec<DbEmail>> {
     Ok(emails)
 }
 
-pub fn update_email_category(id: i32, category: &str) -> Result<()> {
-    let conn = Connection::open("neural-mail.db")?;
-    conn.execute(
-        "UPDATE emails SET category = ?1 WHERE id = ?2",
-        params![category, id],
+/// Emails by id in the order given, with bodies; ids that no longer exist are skipped.
+pub fn get_emails_by_ids(ids: &[i64]) -> Result<Vec<DbEmail>> {
+    let conn = open()?;
+    let mut stmt = conn.prepare_cached(&format!("SELECT {} FROM emails e WHERE e.id = ?1", EMAIL_COLUMNS))?;
+    let mut emails = Vec::new();
+    for id in ids {
+        if let Some(email) = stmt.query_map(params![id], email_from_row)?.next() {
+            emails.push(email?);
+        }
+    }
+    Ok(emails)
+}
+
+/// Ids of the best `limit` full-text matches for an FTS5 `MATCH` expression
+/// in `account`, or in every account when it is empty.
+pub fn search_email_ids(fts_query: &str, account: &str, limit: usize) -> Result<Vec<i64>> {
+    let conn = open()?;
+    let mut stmt = conn.prepare_cached(
+        "SELECT e.id FROM emails_fts JOIN emails e ON e.id = emails_fts.rowid
+         WHERE emails_fts MATCH ?1 AND (?2 = '' OR e.account = ?2)
+         ORDER BY bm25(emails_fts, 10.0, 5.0, 2.0, 1.0) LIMIT ?3",
     )?;
+    let ids = stmt.query_map(params![fts_query, account, limit as i64], |row| row.get(0))?;
+    ids.collect()
+}
+
+/// The newest `limit` emails without an embedding from `model`, as the text
+/// to embed: subject, sender and the start of the body.
+pub fn get_emails_to_embed(model: &str, limit: usize) -> Result<Vec<(i64, String)>> {
+    let conn = open()?;
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT e.id, e.subject || char(10) || e.sender || char(10) || substr(e.body, 1, 2000)
+         FROM emails e LEFT JOIN email_embeddings v ON v.email_id = e.id AND v.model = ?1
+         WHERE v.email_id IS NULL ORDER BY {} LIMIT ?2",
+        SortOrder::Date.order_by()
+    ))?;
+    let rows = stmt.query_map(params![model, limit as i64], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    rows.collect()
+}
+
+pub fn save_embeddings(model: &str, vectors: &[(i64, Vec<f32>)]) -> Result<()> {
+    let mut conn = open()?;
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare_cached(
+            "INSERT OR REPLACE INTO email_embeddings (email_id, model, vector) VALUES (?1, ?2, ?3)",
+        )?;
+        for (email_id, vector) in vectors {
+            let bytes: Vec<u8> = vector.iter().flat_map(|v| v.to_le_bytes()).collect();
+            stmt.execute(params![email_id, model, bytes])?;
+        }
+    }
+    tx.commit()?;
     Ok(())
 }
 
-pub fn get_emails_by_category(category: &str) -> Result<Vec<DbEmail>> {
-    let conn = Connection::open("neural-mail.db")?;
-    let mut stmt = conn.prepare(
-        "SELECT id, subject, sender, date_str, body, has_attachment, category FROM emails 
-         WHERE category = ?1",
+/// Passes each stored embedding from `model` for the emails of `account` (or
+/// of every account when it is empty) to `visit`, one row at a time, so a
+/// nearest-neighbour scan never holds more than one vector.
+pub fn scan_embeddings(model: &str, account: &str, mut visit: impl FnMut(i64, &[f32])) -> Result<()> {
+    let conn = open()?;
+    let mut stmt = conn.prepare_cached(
+        "SELECT v.email_id, v.vector FROM email_embeddings v JOIN emails e ON e.id = v.email_id
+         WHERE v.model = ?1 AND (?2 = '' OR e.account = ?2)",
     )?;
-    let email_iter = stmt.query_map(params![category], |row| {
-        Ok(DbEmail {
+    let mut rows = stmt.query(params![model, account])?;
+    let mut vector = Vec::new();
+    while let Some(row) = rows.next()? {
+        let ValueRef::Blob(bytes) = row.get_ref(1)? else {
+            continue;
+        };
+        vector.clear();
+        vector.extend(bytes.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])));
+        visit(row.get(0)?, &vector);
+    }
+    Ok(())
+}
+
+/// Applies the categorizer's labels to an email and marks it as categorized,
+/// keeping any labels it already had.
+pub fn apply_categorized_labels(email_id: i32, label_ids: &[i64]) -> Result<()> {
+    let mut conn = open()?;
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare_cached("INSERT OR IGNORE INTO email_labels (email_id, label_id) VALUES (?1, ?2)")?;
+        for label_id in label_ids {
+            stmt.execute(params![email_id, label_id])?;
+        }
+    }
+    tx.execute("UPDATE emails SET categorized = 1 WHERE id = ?1", params![email_id])?;
+    tx.commit()?;
+    Ok(())
+}
+
+pub fn set_email_label(email_id: i32, label_id: i64, applied: bool) -> Result<()> {
+    let conn = open()?;
+    if applied {
+        conn.execute(
+            "INSERT OR IGNORE INTO email_labels (email_id, label_id) VALUES (?1, ?2)",
+            params![email_id, label_id],
+        )?;
+    } else {
+        conn.execute(
+            "DELETE FROM email_labels WHERE email_id = ?1 AND label_id = ?2",
+            params![email_id, label_id],
+        )?;
+    }
+    Ok(())
+}
+
+pub fn get_email_label_ids(email_id: i32) -> Result<Vec<i64>> {
+    let conn = open()?;
+    let mut stmt = conn.prepare_cached("SELECT label_id FROM email_labels WHERE email_id = ?1")?;
+    let ids = stmt.query_map(params![email_id], |row| row.get(0))?;
+    ids.collect()
+}
+
+pub fn get_labels() -> Result<Vec<DbLabel>> {
+    let conn = open()?;
+    let mut stmt = conn.prepare_cached("SELECT id, name, color FROM labels ORDER BY name")?;
+    let labels = stmt.query_map([], |row| {
+        Ok(DbLabel {
             id: row.get(0)?,
-            subject: row.get(1)?,
-            sender: row.get(2)?,
-            date: row.get(3)?,
-            body: row.get(4)?,
-            has_attachment: row.get::<_, i32>(5)? == 1,
-            category: row.get(6)?,
+            name: row.get(1)?,
+            color: row.get(2)?,
         })
     })?;
+    labels.collect()
+}
 
-    let mut emails = Vec::new();
-    for email in email_iter {
-        emails.push(email?);
+/// Fails with a constraint error if a label of that name (ignoring case) exists.
+pub fn create_label(name: &str, color: &str) -> Result<i64> {
+    let conn = open()?;
+    conn.execute("INSERT INTO labels (name, color) VALUES (?1, ?2)", params![name, color])?;
+    Ok(conn.last_insert_rowid())
+}
+
+pub fn rename_label(id: i64, name: &str) -> Result<()> {
+    let conn = open()?;
+    conn.execute("UPDATE labels SET name = ?1 WHERE id = ?2", params![name, id])?;
+    Ok(())
+}
+
+pub fn set_label_color(id: i64, color: &str) -> Result<()> {
+    let conn = open()?;
+    conn.execute("UPDATE labels SET color = ?1 WHERE id = ?2", params![color, id])?;
+    Ok(())
+}
+
+/// Deletes a label and removes it from every email.
+pub fn delete_label(id: i64) -> Result<()> {
+    let conn = open()?;
+    conn.execute("DELETE FROM labels WHERE id = ?1", params![id])?;
+    Ok(())
+}
+
+pub fn get_saved_searches() -> Result<Vec<DbSavedSearch>> {
+    let conn = open()?;
+    let mut stmt = conn.prepare_cached("SELECT id, name, query FROM saved_searches ORDER BY name")?;
+    let searches = stmt.query_map([], |row| {
+        Ok(DbSavedSearch {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            query: row.get(2)?,
+        })
+    })?;
+    searches.collect()
+}
+
+/// Fails with a constraint error if a saved search of that name (ignoring case) exists.
+pub fn create_saved_search(name: &str, query: &str) -> Result<i64> {
+    let conn = open()?;
+    conn.execute("INSERT INTO saved_searches (name, query) VALUES (?1, ?2)", params![name, query])?;
+    Ok(conn.last_insert_rowid())
+}
+
+pub fn delete_saved_search(id: i64) -> Result<()> {
+    let conn = open()?;
+    conn.execute("DELETE FROM saved_searches WHERE id = ?1", params![id])?;
+    Ok(())
+}
+
+/// Replaces the stored folder list of `account`. Messages and sync state of
+/// folders that disappeared from the server are dropped with it.
+pub fn save_mailboxes(account: &str, mailboxes: &[DbMailbox]) -> Result<()> {
+    let mut conn = open()?;
+    let tx = conn.transaction()?;
+    tx.execute("DELETE FROM mailboxes WHERE account = ?1", params![account])?;
+    {
+        let mut stmt = tx.prepare_cached(
+            "INSERT INTO mailboxes (account, name, delimiter, special_use, selectable) VALUES (?1, ?2, ?3, ?4, ?5)",
+        )?;
+        for mailbox in mailboxes {
+            stmt.execute(params![
+                account,
+                mailbox.name,
+                mailbox.delimiter,
+                mailbox.special_use,
+                if mailbox.selectable { 1 } else { 0 }
+            ])?;
+        }
     }
-    Ok(emails)
+    tx.execute(
+        "DELETE FROM emails WHERE account = ?1 AND uid IS NOT NULL
+         AND mailbox NOT IN (SELECT name FROM mailboxes WHERE account = ?1)",
+        params![account],
+    )?;
+    tx.execute(
+        "DELETE FROM sync_state WHERE account = ?1 AND mailbox NOT IN (SELECT name FROM mailboxes WHERE account = ?1)",
+        params![account],
+    )?;
+    tx.commit()?;
+    Ok(())
+}
+
+pub fn get_mailboxes(account: &str) -> Result<Vec<DbMailbox>> {
+    let conn = open()?;
+    let mut stmt = conn.prepare_cached(
+        "SELECT name, delimiter, special_use, selectable FROM mailboxes WHERE account = ?1 ORDER BY name",
+    )?;
+    let mailbox_iter = stmt.query_map(params![account], |row| {
+        Ok(DbMailbox {
+            name: row.get(0)?,
+            delimiter: row.get(1)?,
+            special_use: row.get(2)?,
+            selectable: row.get::<_, i32>(3)? == 1,
+        })
+    })?;
+
+    let mut mailboxes = Vec::new();
+    for mailbox in mailbox_iter {
+        mailboxes.push(mailbox?);
+    }
+    Ok(mailboxes)
 }
 
 pub fn count_emails() -> Result<i64> {
-    let conn = Connection::open("neural-mail.db")?;
-    let mut stmt = conn.prepare("SELECT COUNT(*) FROM emails")?;
+    let conn = open()?;
+    let mut stmt = conn.prepare_cached("SELECT COUNT(*) FROM emails")?;
     let mut rows = stmt.query([])?;
 
     if let Some(row) = rows.next()? {
@@ -215,7 +1182,7 @@ pub fn count_emails() -> Result<i64> {
 }
 
 pub fn save_sidebar_width(width: f32) -> Result<()> {
-    let conn = Connection::open("neural-mail.db")?;
+    let conn = open()?;
     conn.execute(
         "INSERT INTO settings (key, value) VALUES ('sidebar_width', ?1)
          ON CONFLICT(key) DO UPDATE SET value=excluded.value",
@@ -225,8 +1192,8 @@ pub fn save_sidebar_width(width: f32) -> Result<()> {
 }
 
 pub fn get_sidebar_width() -> Result<f32> {
-    let conn = Connection::open("neural-mail.db")?;
-    let mut stmt = conn.prepare("SELECT value FROM settings WHERE key = 'sidebar_width'")?;
+    let conn = open()?;
+    let mut stmt = conn.prepare_cached("SELECT value FROM settings WHERE key = 'sidebar_width'")?;
     let mut rows = stmt.query([])?;
 
     if let Some(row) = rows.next()? {
@@ -240,8 +1207,55 @@ pub fn get_sidebar_width() -> Result<f32> {
     Ok(570.0)
 }
 
+pub fn save_auto_lock_minutes(minutes: i32) -> Result<()> {
+    let conn = open()?;
+    conn.execute(
+        "INSERT INTO settings (key, value) VALUES ('auto_lock_minutes', ?1)
+         ON CONFLICT(key) DO UPDATE SET value=excluded.value",
+        params![minutes.to_string()],
+    )?;
+    Ok(())
+}
+
+/// Minutes of inactivity before the app locks itself; 0 disables auto-lock.
+pub fn get_auto_lock_minutes() -> Result<i32> {
+    let conn = open()?;
+    let mut stmt = conn.prepare_cached("SELECT value FROM settings WHERE key = 'auto_lock_minutes'")?;
+    let mut rows = stmt.query([])?;
+
+    if let Some(row) = rows.next()? {
+        let val_str: String = row.get(0)?;
+        if let Ok(minutes) = val_str.parse::<i32>() {
+            return Ok(minutes);
+        }
+    }
+    Ok(10)
+}
+
+pub fn save_sort_order(sort: SortOrder) -> Result<()> {
+    let conn = open()?;
+    conn.execute(
+        "INSERT INTO settings (key, value) VALUES ('sort_order', ?1)
+         ON CONFLICT(key) DO UPDATE SET value=excluded.value",
+        params![sort.name()],
+    )?;
+    Ok(())
+}
+
+pub fn get_sort_order() -> Result<SortOrder> {
+    let conn = open()?;
+    let mut stmt = conn.prepare_cached("SELECT value FROM settings WHERE key = 'sort_order'")?;
+    let mut rows = stmt.query([])?;
+
+    if let Some(row) = rows.next()? {
+        let val_str: String = row.get(0)?;
+        return Ok(SortOrder::from_name(&val_str));
+    }
+    Ok(SortOrder::default())
+}
+
 pub fn save_theme_mode(mode: &str) -> Result<()> {
-    let conn = Connection::open("neural-mail.db")?;
+    let conn = open()?;
     conn.execute(
         "INSERT INTO settings (key, value) VALUES ('theme_mode', ?1)
          ON CONFLICT(key) DO UPDATE SET value=excluded.value",
@@ -251,8 +1265,8 @@ pub fn save_theme_mode(mode: &str) -> Result<()> {
 }
 
 pub fn get_theme_mode() -> Result<String> {
-    let conn = Connection::open("neural-mail.db")?;
-    let mut stmt = conn.prepare("SELECT value FROM settings WHERE key = 'theme_mode'")?;
+    let conn = open()?;
+    let mut stmt = conn.prepare_cached("SELECT value FROM settings WHERE key = 'theme_mode'")?;
     let mut rows = stmt.query([])?;
 
     if let Some(row) = rows.next()? {
@@ -262,3 +1276,24 @@ pub fn get_theme_mode() -> Result<String> {
     // Default mode
     Ok("system".to_string())
 }
+
+pub fn save_setting(key: &str, value: &str) -> Result<()> {
+    let conn = open()?;
+    conn.execute(
+        "INSERT INTO settings (key, value) VALUES (?1, ?2)
+         ON CONFLICT(key) DO UPDATE SET value=excluded.value",
+        params![key, value],
+    )?;
+    Ok(())
+}
+
+pub fn get_setting(key: &str) -> Result<Option<String>> {
+    let conn = open()?;
+    let mut stmt = conn.prepare_cached("SELECT value FROM settings WHERE key = ?1")?;
+    let mut rows = stmt.query(params![key])?;
+
+    if let Some(row) = rows.next()? {
+        return Ok(Some(row.get(0)?));
+    }
+    Ok(None)
+}
@@ -0,0 +1,250 @@
+use crate::db::{self, DbEmail, DbLabel, EmailList, ListCursor, ListPage, PageStart, SortOrder};
+use crate::{Email, Label, SnippetPart};
+use chrono::{DateTime, Datelike, Local, TimeDelta};
+use slint::{Model, ModelNotify, ModelRc, ModelTracker, VecModel};
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::rc::{Rc, Weak};
+use tracing::error;
+
+/// Rows read from SQLite per query.
+pub const PAGE_SIZE: usize = 100;
+/// Pages kept in memory; the least recently used is dropped and read again if
+/// the list is scrolled back to it.
+const CACHED_PAGES: usize = 8;
+
+/// The email list as a `slint::Model` that reads pages of headers from SQLite
+/// as the list view asks for rows, so memory stays flat however large the
+/// mailbox is. Pages are read on the runtime; until one arrives its rows are
+/// blank placeholders. Bodies are not part of the rows; see `db::get_email_body`.
+pub struct EmailListModel {
+    list: EmailList,
+    sort: SortOrder,
+    count: usize,
+    /// Reference point for relative dates, fixed so rows agree with each other.
+    now: DateTime<Local>,
+    labels: HashMap<i64, DbLabel>,
+    cache: RefCell<PageCache>,
+    /// Pages being read.
+    loading: RefCell<HashSet<usize>>,
+    /// Sort key of the last row of each page read so far, so the next page
+    /// can be read from there instead of by offset.
+    cursors: RefCell<HashMap<usize, ListCursor>>,
+    rt: tokio::runtime::Handle,
+    this: Weak<EmailListModel>,
+    notify: ModelNotify,
+}
+
+#[derive(Default)]
+struct PageCache {
+    pages: HashMap<usize, Vec<Email>>,
+    /// Page numbers, least recently used first.
+    order: VecDeque<usize>,
+}
+
+impl PageCache {
+    fn get(&mut self, page: usize) -> Option<&Vec<Email>> {
+        if let Some(position) = self.order.iter().position(|&p| p == page) {
+            self.order.remove(position);
+            self.order.push_back(page);
+        }
+        self.pages.get(&page)
+    }
+
+    fn insert(&mut self, page: usize, rows: Vec<Email>) {
+        if self.order.len() >= CACHED_PAGES {
+            if let Some(oldest) = self.order.pop_front() {
+                self.pages.remove(&oldest);
+            }
+        }
+        self.order.push_back(page);
+        self.pages.insert(page, rows);
+    }
+}
+
+impl EmailListModel {
+    /// `first_page` is page 0 of `list`, usually read together with `count`
+    /// and the label set off the UI thread. Further pages are read on `rt`.
+    pub fn new(
+        rt: tokio::runtime::Handle,
+        list: EmailList,
+        sort: SortOrder,
+        count: usize,
+        first_page: ListPage,
+        labels: Vec<DbLabel>,
+    ) -> Rc<Self> {
+        let model = Rc::new_cyclic(|this| EmailListModel {
+            list,
+            sort,
+            count,
+            now: Local::now(),
+            labels: labels.into_iter().map(|l| (l.id, l)).collect(),
+            cache: RefCell::default(),
+            loading: RefCell::default(),
+            cursors: RefCell::default(),
+            rt,
+            this: this.clone(),
+            notify: ModelNotify::default(),
+        });
+        model.page_loaded(0, first_page);
+        model
+    }
+
+    /// Starts reading `page` unless it is already on its way. Continues from
+    /// the previous page's last row when that is known, by offset otherwise
+    /// (e.g. when the scrollbar is dragged far down).
+    fn load_page(&self, page: usize) {
+        if !self.loading.borrow_mut().insert(page) {
+            return;
+        }
+        let start = match page.checked_sub(1).and_then(|previous| self.cursors.borrow().get(&previous).cloned()) {
+            Some(cursor) => PageStart::After(cursor),
+            None => PageStart::Offset(page * PAGE_SIZE),
+        };
+        let (list, sort) = (self.list.clone(), self.sort);
+        let read = self.rt.spawn(db::call(move || db::list_headers(&list, sort, &start, PAGE_SIZE)));
+        let this = self.this.clone();
+        let spawned = slint::spawn_local(async move {
+            let result = read.await.map_err(|e| e.to_string()).and_then(|r| r.map_err(|e| e.to_string()));
+            // The list may have been replaced in the meantime
+            let Some(model) = this.upgrade() else {
+                return;
+            };
+            model.loading.borrow_mut().remove(&page);
+            match result {
+                Ok(rows) => model.page_loaded(page, rows),
+                Err(e) => error!("Failed to load email list page {}: {}", page, e),
+            }
+        });
+        if let Err(e) = spawned {
+            error!("Failed to load email list page {}: {}", page, e);
+            self.loading.borrow_mut().remove(&page);
+        }
+    }
+
+    fn page_loaded(&self, page: usize, loaded: ListPage) {
+        if let Some(last) = loaded.last {
+            self.cursors.borrow_mut().insert(page, last);
+        }
+        let rows: Vec<Email> = loaded.emails.into_iter().map(|e| self.email_row(e)).collect();
+        let first_row = page * PAGE_SIZE;
+        let end_row = (first_row + rows.len()).min(self.count);
+        self.cache.borrow_mut().insert(page, rows);
+        for row in first_row..end_row {
+            self.notify.row_changed(row);
+        }
+    }
+
+    fn email_row(&self, e: DbEmail) -> Email {
+        let labels: Vec<Label> = e.label_ids.iter().filter_map(|id| self.labels.get(id)).map(label_row).collect();
+        Email {
+            id: e.id,
+            subject: e.subject.into(),
+            sender: e.sender.into(),
+            date: format_list_date(e.received_at, &e.date, self.now).into(),
+            date_full: format_full_date(e.received_at, &e.date).into(),
+            has_attachment: e.has_attachment,
+            labels: ModelRc::from(Rc::new(VecModel::from(labels))),
+            account: e.account.into(),
+            snippet: snippet_to_model(e.snippet.as_deref().unwrap_or("")),
+        }
+    }
+}
+
+impl Model for EmailListModel {
+    type Data = Email;
+
+    fn row_count(&self) -> usize {
+        self.count
+    }
+
+    fn row_data(&self, row: usize) -> Option<Email> {
+        if row >= self.count {
+            return None;
+        }
+        let page = row / PAGE_SIZE;
+        let cached = self.cache.borrow_mut().get(page).map(|rows| rows.get(row % PAGE_SIZE).cloned());
+        match cached {
+            Some(email) => email,
+            None => {
+                self.load_page(page);
+                // Blank until the page arrives; id 0 is never a stored email
+                Some(Email::default())
+            }
+        }
+    }
+
+    fn model_tracker(&self) -> &dyn ModelTracker {
+        &self.notify
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+pub fn label_row(label: &DbLabel) -> Label {
+    Label {
+        id: label.id as i32,
+        name: label.name.as_str().into(),
+        color: parse_color(&label.color),
+    }
+}
+
+/// `#rrggbb` as a Slint color; grey if malformed.
+fn parse_color(hex: &str) -> slint::Color {
+    u32::from_str_radix(hex.trim_start_matches('#'), 16)
+        .map(|rgb| slint::Color::from_argb_encoded(0xff00_0000 | rgb))
+        .unwrap_or(slint::Color::from_rgb_u8(0x88, 0x88, 0x88))
+}
+
+/// Short date for a list row, in local time: minutes for the last hour, the
+/// time today, the weekday within the last week, then the day and month (with
+/// the year once it differs). Falls back to the stored Date header text.
+fn format_list_date(received_at: Option<i64>, fallback: &str, now: DateTime<Local>) -> String {
+    let Some(when) = received_at.and_then(|ts| DateTime::from_timestamp(ts, 0)) else {
+        return fallback.to_string();
+    };
+    let when = when.with_timezone(&Local);
+    let age = now.signed_duration_since(when);
+
+    if age >= TimeDelta::zero() && age < TimeDelta::minutes(1) {
+        "Just now".to_string()
+    } else if age >= TimeDelta::zero() && age < TimeDelta::hours(1) {
+        format!("{}m ago", age.num_minutes())
+    } else if when.date_naive() == now.date_naive() {
+        when.format("%H:%M").to_string()
+    } else if age >= TimeDelta::zero() && age < TimeDelta::days(7) {
+        when.format("%a %H:%M").to_string()
+    } else if when.year() == now.year() {
+        when.format("%b %-d").to_string()
+    } else {
+        when.format("%Y-%m-%d").to_string()
+    }
+}
+
+/// Full local date and time for the reading pane.
+fn format_full_date(received_at: Option<i64>, fallback: &str) -> String {
+    match received_at.and_then(|ts| DateTime::from_timestamp(ts, 0)) {
+        Some(when) => when.with_timezone(&Local).format("%a, %-d %b %Y %H:%M").to_string(),
+        None => fallback.to_string(),
+    }
+}
+
+/// Splits a search snippet on the hit markers into plain and highlighted runs.
+fn snippet_to_model(snippet: &str) -> ModelRc<SnippetPart> {
+    let mut parts = Vec::new();
+    let mut hit = false;
+    for piece in snippet.split([db::SNIPPET_START, db::SNIPPET_END]) {
+        if !piece.is_empty() {
+            // Newlines would break the single-line row layout
+            let text = piece.split_whitespace().collect::<Vec<_>>().join(" ");
+            let padded = if piece.starts_with(char::is_whitespace) { format!(" {}", text) } else { text };
+            let padded = if piece.ends_with(char::is_whitespace) { format!("{} ", padded) } else { padded };
+            parts.push(SnippetPart { text: padded.into(), hit });
+        }
+        hit = !hit;
+    }
+    ModelRc::from(Rc::new(VecModel::from(parts)))
+}
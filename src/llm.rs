@@ -0,0 +1,703 @@
+use crate::db;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{debug, error, warn};
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// One prompt for a model, independent of the server's wire format.
+#[derive(Debug, Clone, Default)]
+pub struct CompletionRequest {
+    /// Instructions sent ahead of the prompt, as a system message where the
+    /// server has them.
+    pub system: Option<String>,
+    pub prompt: String,
+    /// Model to use instead of the backend's default.
+    pub model: Option<String>,
+    /// Context window to ask for, in tokens; ignored by servers that fix it at load time.
+    pub context_tokens: Option<u32>,
+    pub temperature: Option<f32>,
+    /// Longest wait for the next piece of the reply.
+    pub timeout: Option<Duration>,
+}
+
+/// Receives each piece of a reply as the server produces it.
+pub type TokenSink<'a> = dyn FnMut(&str) + Send + 'a;
+
+/// A language model server. The `ai` module builds prompts and hands them to
+/// whichever backend is selected in settings.
+pub trait LlmBackend: Send + Sync {
+    /// Returns the whole reply, passing each piece to `on_token` as it arrives.
+    fn stream<'a>(&'a self, request: CompletionRequest, on_token: &'a mut TokenSink<'a>) -> BoxFuture<'a, Result<String, String>>;
+
+    fn complete<'a>(&'a self, request: CompletionRequest) -> BoxFuture<'a, Result<String, String>> {
+        Box::pin(async move { self.stream(request, &mut |_: &str| {}).await })
+    }
+
+    /// Names of the models the server can run.
+    fn list_models(&self) -> BoxFuture<'_, Result<Vec<String>, String>>;
+
+    /// One embedding vector per text, from `model` (or the backend's default model).
+    fn embed<'a>(&'a self, model: Option<&'a str>, texts: &'a [String], timeout: Duration) -> BoxFuture<'a, Result<Vec<Vec<f32>>, String>>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BackendKind {
+    #[default]
+    Ollama,
+    /// Any server exposing `/v1/chat/completions`: LM Studio, vLLM, llama.cpp server.
+    OpenAi,
+    Fake,
+}
+
+impl BackendKind {
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "openai" => BackendKind::OpenAi,
+            "fake" => BackendKind::Fake,
+            _ => BackendKind::Ollama,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            BackendKind::Ollama => "ollama",
+            BackendKind::OpenAi => "openai",
+            BackendKind::Fake => "fake",
+        }
+    }
+
+    pub fn default_endpoint(self) -> String {
+        match self {
+            BackendKind::Ollama => env::var("OLLAMA_ENDPOINT")
+                .unwrap_or_else(|_| "http://localhost:11434/api/generate".to_string()),
+            BackendKind::OpenAi => env::var("OPENAI_BASE_URL")
+                .unwrap_or_else(|_| "http://localhost:8080/v1".to_string()),
+            BackendKind::Fake => String::new(),
+        }
+    }
+}
+
+pub const DEFAULT_MODEL: &str = "llama3.1:latest";
+pub const DEFAULT_EMBEDDING_MODEL: &str = "nomic-embed-text";
+
+/// The jobs the `ai` module gives a model, each with its own settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Task {
+    Summary,
+    Chat,
+    Reply,
+    Categorize,
+    /// Vectors for finding the emails a chat question is about.
+    Embedding,
+}
+
+impl Task {
+    pub const ALL: [Task; 5] = [Task::Summary, Task::Chat, Task::Reply, Task::Categorize, Task::Embedding];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Task::ALL.into_iter().find(|task| task.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Task::Summary => "summary",
+            Task::Chat => "chat",
+            Task::Reply => "reply",
+            Task::Categorize => "categorize",
+            Task::Embedding => "embedding",
+        }
+    }
+
+    /// Label for the settings screen.
+    pub fn title(self) -> &'static str {
+        match self {
+            Task::Summary => "Summaries",
+            Task::Chat => "Chat",
+            Task::Reply => "Reply drafts",
+            Task::Categorize => "Labelling",
+            Task::Embedding => "Embeddings",
+        }
+    }
+
+    fn defaults(self) -> TaskSettings {
+        match self {
+            // Room for several emails of context
+            Task::Chat => TaskSettings { context_tokens: Some(8192), ..TaskSettings::default() },
+            // Chat models make poor embedders
+            Task::Embedding => TaskSettings { model: DEFAULT_EMBEDDING_MODEL.to_string(), ..TaskSettings::default() },
+            _ => TaskSettings::default(),
+        }
+    }
+
+    fn setting_key(self) -> String {
+        format!("llm_task_{}", self.name())
+    }
+}
+
+/// Model and parameters for one task. An empty `model` means the default
+/// model; `None` leaves a parameter to the server.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TaskSettings {
+    pub model: String,
+    pub context_tokens: Option<u32>,
+    pub temperature: Option<f32>,
+    pub timeout_secs: u64,
+}
+
+impl Default for TaskSettings {
+    fn default() -> Self {
+        TaskSettings { model: String::new(), context_tokens: None, temperature: None, timeout_secs: 60 }
+    }
+}
+
+impl TaskSettings {
+    /// Reads the fields as typed on the settings screen; blank numbers leave
+    /// the parameter to the server (or the default timeout).
+    pub fn parse(model: &str, context_tokens: &str, temperature: &str, timeout_secs: &str) -> Result<Self, String> {
+        let context_tokens = match context_tokens.trim() {
+            "" => None,
+            text => match text.parse::<u32>() {
+                Ok(tokens) if tokens >= 512 => Some(tokens),
+                _ => return Err("The context size must be a whole number of tokens, at least 512.".into()),
+            },
+        };
+        let temperature = match temperature.trim() {
+            "" => None,
+            text => match text.parse::<f32>() {
+                Ok(t) if (0.0..=2.0).contains(&t) => Some(t),
+                _ => return Err("The temperature must be a number from 0 to 2.".into()),
+            },
+        };
+        let timeout_secs = match timeout_secs.trim() {
+            "" => TaskSettings::default().timeout_secs,
+            text => match text.parse::<u64>() {
+                Ok(secs) if (1..=3600).contains(&secs) => secs,
+                _ => return Err("The timeout must be from 1 to 3600 seconds.".into()),
+            },
+        };
+        Ok(TaskSettings { model: model.trim().to_string(), context_tokens, temperature, timeout_secs })
+    }
+}
+
+/// Backend choice and per-task settings as stored in the settings table.
+/// Empty `endpoint` and `model` mean the defaults for the backend.
+#[derive(Debug, Clone, Default)]
+pub struct LlmSettings {
+    pub backend: BackendKind,
+    pub endpoint: String,
+    pub model: String,
+    pub tasks: HashMap<Task, TaskSettings>,
+}
+
+impl LlmSettings {
+    pub fn load() -> Self {
+        let get = |key: &str| db::get_setting(key).ok().flatten().unwrap_or_default();
+        let tasks = Task::ALL
+            .into_iter()
+            .filter_map(|task| {
+                let stored = db::get_setting(&task.setting_key()).ok().flatten()?;
+                serde_json::from_str(&stored).ok().map(|settings| (task, settings))
+            })
+            .collect();
+        LlmSettings {
+            backend: BackendKind::from_name(&get("llm_backend")),
+            endpoint: get("llm_endpoint"),
+            model: get("llm_model"),
+            tasks,
+        }
+    }
+
+    /// Settings for `task`, or its defaults if none were saved.
+    pub fn task(&self, task: Task) -> TaskSettings {
+        self.tasks.get(&task).cloned().unwrap_or_else(|| task.defaults())
+    }
+
+    fn save(&self) -> Result<(), String> {
+        db::save_setting("llm_backend", self.backend.name())
+            .and_then(|_| db::save_setting("llm_endpoint", self.endpoint.trim()))
+            .and_then(|_| db::save_setting("llm_model", self.model.trim()))
+            .map_err(|e| format!("Failed to save AI settings: {}", e))?;
+        for (task, settings) in &self.tasks {
+            let json = serde_json::to_string(settings).map_err(|e| e.to_string())?;
+            db::save_setting(&task.setting_key(), &json).map_err(|e| format!("Failed to save AI settings: {}", e))?;
+        }
+        Ok(())
+    }
+
+    fn build(&self) -> Arc<dyn LlmBackend> {
+        let endpoint = match self.endpoint.trim() {
+            "" => self.backend.default_endpoint(),
+            endpoint => endpoint.to_string(),
+        };
+        let model = match self.model.trim() {
+            "" => DEFAULT_MODEL.to_string(),
+            model => model.to_string(),
+        };
+        match self.backend {
+            BackendKind::Ollama => Arc::new(OllamaBackend { endpoint, model }),
+            BackendKind::OpenAi => Arc::new(OpenAiBackend {
+                endpoint,
+                model,
+                api_key: env::var("OPENAI_API_KEY").ok().filter(|k| !k.is_empty()),
+            }),
+            BackendKind::Fake => Arc::new(FakeBackend),
+        }
+    }
+}
+
+/// The settings in use and the backend built from them.
+struct Active {
+    settings: LlmSettings,
+    backend: Arc<dyn LlmBackend>,
+}
+
+static ACTIVE: RwLock<Option<Arc<Active>>> = RwLock::new(None);
+
+/// Settings and backend, loaded from the settings table on first use.
+fn active() -> Arc<Active> {
+    if let Some(active) = ACTIVE.read().unwrap().as_ref() {
+        return active.clone();
+    }
+    let settings = LlmSettings::load();
+    let active = Arc::new(Active { backend: settings.build(), settings });
+    *ACTIVE.write().unwrap() = Some(active.clone());
+    active
+}
+
+/// The selected backend.
+pub fn backend() -> Arc<dyn LlmBackend> {
+    active().backend.clone()
+}
+
+pub fn task_settings(task: Task) -> TaskSettings {
+    active().settings.task(task)
+}
+
+/// The model `task` runs on, as the backend will resolve it.
+pub fn model_for(task: Task) -> String {
+    let active = active();
+    if active.settings.backend == BackendKind::Fake {
+        return "fake".to_string();
+    }
+    [active.settings.task(task).model.as_str(), active.settings.model.trim()]
+        .into_iter()
+        .find(|model| !model.is_empty())
+        .unwrap_or(DEFAULT_MODEL)
+        .to_string()
+}
+
+/// Embeds `texts` with the model and timeout configured for embeddings.
+pub async fn embed(texts: &[String]) -> Result<Vec<Vec<f32>>, String> {
+    let settings = task_settings(Task::Embedding);
+    let model = Some(settings.model).filter(|m| !m.is_empty());
+    let vectors = backend().embed(model.as_deref(), texts, Duration::from_secs(settings.timeout_secs)).await?;
+    if vectors.len() != texts.len() {
+        return Err(format!("Expected {} embeddings, got {}", texts.len(), vectors.len()));
+    }
+    Ok(vectors)
+}
+
+/// A request for `task` carrying the model and parameters configured for it.
+pub fn request_for(task: Task, system: Option<String>, prompt: String) -> CompletionRequest {
+    let settings = active().settings.task(task);
+    CompletionRequest {
+        system,
+        prompt,
+        model: Some(settings.model).filter(|m| !m.is_empty()),
+        context_tokens: settings.context_tokens,
+        temperature: settings.temperature,
+        timeout: Some(Duration::from_secs(settings.timeout_secs)),
+    }
+}
+
+/// Saves `settings` and switches later requests to the backend they describe.
+pub fn configure(settings: LlmSettings) -> Result<(), String> {
+    let endpoint = settings.endpoint.trim();
+    if !endpoint.is_empty() && !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+        return Err("The endpoint must be an http:// or https:// URL.".into());
+    }
+    settings.save()?;
+    *ACTIVE.write().unwrap() = Some(Arc::new(Active { backend: settings.build(), settings }));
+    Ok(())
+}
+
+/// Drops the cached settings so the next request reads them again, e.g.
+/// after a different profile is unlocked.
+pub fn reset() {
+    *ACTIVE.write().unwrap() = None;
+}
+
+/// Answers every request with `FakeBackend`, without touching the settings table.
+#[cfg(test)]
+pub fn use_fake_backend() {
+    let settings = LlmSettings { backend: BackendKind::Fake, ..LlmSettings::default() };
+    *ACTIVE.write().unwrap() = Some(Arc::new(Active { backend: settings.build(), settings }));
+}
+
+/// Models available from the backend `settings` describe, which need not be
+/// the one in use (the settings screen asks before saving).
+pub async fn list_models(settings: &LlmSettings) -> Result<Vec<String>, String> {
+    let backend = settings.build();
+    let mut models = backend.list_models().await?;
+    models.sort();
+    Ok(models)
+}
+
+/// Default wait for the response to start and for each further piece of a
+/// streamed reply.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn http_client() -> Result<reqwest::Client, String> {
+    reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(10))
+        .build()
+        .map_err(|e| {
+            error!("Failed to create reqwest client: {}", e);
+            format!("Failed to create client: {}", e)
+        })
+}
+
+/// GETs a JSON document, e.g. a model list.
+async fn get_json(url: &str, api_key: Option<&str>) -> Result<serde_json::Value, String> {
+    let mut request = http_client()?.get(url).timeout(Duration::from_secs(10));
+    if let Some(key) = api_key {
+        request = request.bearer_auth(key);
+    }
+    let res = request.send().await.map_err(|e| format!("AI Service unavailable: {}", e))?;
+    let status = res.status();
+    if !status.is_success() {
+        return Err(format!("AI Service returned {}", status));
+    }
+    res.json().await.map_err(|e| format!("Invalid JSON response: {}", e))
+}
+
+/// POSTs `body` as JSON and returns the JSON response, e.g. embeddings.
+async fn post_json(url: &str, api_key: Option<&str>, body: &impl Serialize, timeout: Duration) -> Result<serde_json::Value, String> {
+    let mut request = http_client()?.post(url).json(body).timeout(timeout);
+    if let Some(key) = api_key {
+        request = request.bearer_auth(key);
+    }
+    let res = request.send().await.map_err(|e| format!("AI Service unavailable: {}", e))?;
+    let status = res.status();
+    let json: serde_json::Value = res.json().await.map_err(|e| format!("Invalid JSON response: {}", e))?;
+    if !status.is_success() {
+        let message = json["error"]["message"].as_str().or_else(|| json["error"].as_str()).unwrap_or("no details");
+        return Err(format!("AI Service returned {}: {}", status, message));
+    }
+    Ok(json)
+}
+
+/// Vectors from a JSON array of number arrays.
+fn parse_vectors<'a>(arrays: impl Iterator<Item = &'a serde_json::Value>) -> Vec<Vec<f32>> {
+    arrays
+        .map(|v| v.as_array().map(|a| a.iter().filter_map(|x| x.as_f64()).map(|x| x as f32).collect()).unwrap_or_default())
+        .collect()
+}
+
+/// POSTs `body` as JSON and passes each line of the response to `on_line` as
+/// it arrives, until `on_line` reports the reply complete or the server ends
+/// it, or nothing arrives for `idle_timeout`, which also bounds the wait for
+/// the response headers. Connection failures are retried up to three times; a
+/// server that does not answer in time and non-2xx responses are returned as
+/// errors without retrying.
+async fn post_lines(
+    endpoint: &str,
+    api_key: Option<&str>,
+    body: &(impl Serialize + Sync),
+    idle_timeout: Option<Duration>,
+    on_line: &mut (dyn FnMut(&str) -> Result<bool, String> + Send),
+) -> Result<(), String> {
+    let client = http_client()?;
+    let idle_timeout = idle_timeout.unwrap_or(IDLE_TIMEOUT);
+
+    let mut last_error = String::new();
+    let mut response = None;
+    for attempt in 1..=3 {
+        debug!("LLM request attempt {} to {}", attempt, endpoint);
+        let mut request = client.post(endpoint).json(body);
+        if let Some(key) = api_key {
+            request = request.bearer_auth(key);
+        }
+        let sent = tokio::time::timeout(idle_timeout, request.send())
+            .await
+            .map_err(|_| "AI Service did not respond in time".to_string())?;
+        match sent {
+            Ok(res) => {
+                response = Some(res);
+                break;
+            }
+            Err(e) => {
+                warn!("LLM request attempt {} failed: {}", attempt, e);
+                last_error = format!("Attempt {}: {}", attempt, e);
+                if attempt < 3 {
+                    tokio::time::sleep(Duration::from_millis(500 * attempt)).await;
+                }
+            }
+        }
+    }
+    let Some(mut res) = response else {
+        error!("LLM request failed after 3 attempts: {}", last_error);
+        return Err(format!("AI Service unavailable after 3 attempts. Last error: {}", last_error));
+    };
+
+    let status = res.status();
+    if !status.is_success() {
+        let text = res.text().await.unwrap_or_default();
+        let json: serde_json::Value = serde_json::from_str(&text).unwrap_or_default();
+        let message = json["error"]["message"].as_str()
+            .or_else(|| json["error"].as_str())
+            .unwrap_or("no details");
+        error!("LLM server returned {}: {}", status, message);
+        return Err(format!("AI Service returned {}: {}", status, message));
+    }
+
+    let mut buffer: Vec<u8> = Vec::new();
+    loop {
+        let chunk = tokio::time::timeout(idle_timeout, res.chunk())
+            .await
+            .map_err(|_| "AI Service stopped responding".to_string())?
+            .map_err(|e| format!("Connection to the AI Service was lost: {}", e))?;
+        let Some(chunk) = chunk else { break };
+        buffer.extend_from_slice(&chunk);
+        while let Some(end) = buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            if !line.trim().is_empty() && on_line(line.trim())? {
+                debug!("LLM request successful");
+                return Ok(());
+            }
+        }
+    }
+    let rest = String::from_utf8_lossy(&buffer);
+    if !rest.trim().is_empty() {
+        on_line(rest.trim())?;
+    }
+    debug!("LLM request successful");
+    Ok(())
+}
+
+fn parse_line(line: &str) -> Result<serde_json::Value, String> {
+    serde_json::from_str(line).map_err(|e| {
+        error!("LLM JSON parse error: {}", e);
+        format!("Invalid JSON response: {}", e)
+    })
+}
+
+#[derive(Debug, Serialize)]
+struct OllamaOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_ctx: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+}
+
+#[derive(Debug, Serialize)]
+struct OllamaRequest<'a> {
+    model: &'a str,
+    prompt: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<&'a str>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    options: Option<OllamaOptions>,
+}
+
+/// Ollama's native `/api/generate`, streamed as one JSON object per line.
+pub struct OllamaBackend {
+    endpoint: String,
+    model: String,
+}
+
+impl OllamaBackend {
+    /// Another API on the same server as the configured `/api/generate` endpoint.
+    fn api_url(&self, path: &str) -> String {
+        let endpoint = self.endpoint.trim_end_matches('/');
+        let base = endpoint.strip_suffix("/api/generate").unwrap_or(endpoint);
+        format!("{}{}", base, path)
+    }
+}
+
+impl LlmBackend for OllamaBackend {
+    fn stream<'a>(&'a self, request: CompletionRequest, on_token: &'a mut TokenSink<'a>) -> BoxFuture<'a, Result<String, String>> {
+        Box::pin(async move {
+            let options = (request.context_tokens.is_some() || request.temperature.is_some())
+                .then_some(OllamaOptions { num_ctx: request.context_tokens, temperature: request.temperature });
+            let body = OllamaRequest {
+                model: request.model.as_deref().unwrap_or(&self.model),
+                prompt: &request.prompt,
+                system: request.system.as_deref(),
+                stream: true,
+                options,
+            };
+            let mut reply = String::new();
+            post_lines(&self.endpoint, None, &body, request.timeout, &mut |line: &str| {
+                let json = parse_line(line)?;
+                if let Some(e) = json["error"].as_str() {
+                    return Err(format!("AI Service error: {}", e));
+                }
+                if let Some(piece) = json["response"].as_str() {
+                    reply.push_str(piece);
+                    on_token(piece);
+                }
+                Ok(json["done"].as_bool().unwrap_or(false))
+            })
+            .await?;
+            Ok(if reply.is_empty() { "No response".to_string() } else { reply })
+        })
+    }
+
+    fn list_models(&self) -> BoxFuture<'_, Result<Vec<String>, String>> {
+        Box::pin(async move {
+            let json = get_json(&self.api_url("/api/tags"), None).await?;
+            Ok(json["models"]
+                .as_array()
+                .map(|models| models.iter().filter_map(|m| m["name"].as_str()).map(str::to_string).collect())
+                .unwrap_or_default())
+        })
+    }
+
+    fn embed<'a>(&'a self, model: Option<&'a str>, texts: &'a [String], timeout: Duration) -> BoxFuture<'a, Result<Vec<Vec<f32>>, String>> {
+        Box::pin(async move {
+            let body = serde_json::json!({ "model": model.unwrap_or(&self.model), "input": texts });
+            let json = post_json(&self.api_url("/api/embed"), None, &body, timeout).await?;
+            Ok(parse_vectors(json["embeddings"].as_array().into_iter().flatten()))
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ChatMessage<'a> {
+    role: &'a str,
+    content: &'a str,
+}
+
+#[derive(Debug, Serialize)]
+struct ChatRequest<'a> {
+    model: &'a str,
+    messages: Vec<ChatMessage<'a>>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+}
+
+/// An OpenAI-compatible server; `endpoint` is the base URL ending in `/v1`.
+/// Replies are streamed as server-sent events. `OPENAI_API_KEY` is sent as a
+/// bearer token when set. The context size is fixed when such servers load a
+/// model, so requests do not carry one.
+pub struct OpenAiBackend {
+    endpoint: String,
+    model: String,
+    api_key: Option<String>,
+}
+
+impl LlmBackend for OpenAiBackend {
+    fn stream<'a>(&'a self, request: CompletionRequest, on_token: &'a mut TokenSink<'a>) -> BoxFuture<'a, Result<String, String>> {
+        Box::pin(async move {
+            let mut messages = Vec::new();
+            if let Some(system) = request.system.as_deref() {
+                messages.push(ChatMessage { role: "system", content: system });
+            }
+            messages.push(ChatMessage { role: "user", content: &request.prompt });
+            let body = ChatRequest {
+                model: request.model.as_deref().unwrap_or(&self.model),
+                messages,
+                stream: true,
+                temperature: request.temperature,
+            };
+
+            let url = format!("{}/chat/completions", self.endpoint.trim_end_matches('/'));
+            let mut reply = String::new();
+            post_lines(&url, self.api_key.as_deref(), &body, request.timeout, &mut |line: &str| {
+                // Comments and other event fields carry no text
+                let Some(data) = line.strip_prefix("data:").map(str::trim) else {
+                    return Ok(false);
+                };
+                if data == "[DONE]" {
+                    return Ok(true);
+                }
+                let json = parse_line(data)?;
+                if let Some(e) = json["error"]["message"].as_str() {
+                    return Err(format!("AI Service error: {}", e));
+                }
+                if let Some(piece) = json["choices"][0]["delta"]["content"].as_str() {
+                    reply.push_str(piece);
+                    on_token(piece);
+                }
+                Ok(false)
+            })
+            .await?;
+            Ok(if reply.is_empty() { "No response".to_string() } else { reply })
+        })
+    }
+
+    fn list_models(&self) -> BoxFuture<'_, Result<Vec<String>, String>> {
+        Box::pin(async move {
+            let url = format!("{}/models", self.endpoint.trim_end_matches('/'));
+            let json = get_json(&url, self.api_key.as_deref()).await?;
+            Ok(json["data"]
+                .as_array()
+                .map(|models| models.iter().filter_map(|m| m["id"].as_str()).map(str::to_string).collect())
+                .unwrap_or_default())
+        })
+    }
+
+    fn embed<'a>(&'a self, model: Option<&'a str>, texts: &'a [String], timeout: Duration) -> BoxFuture<'a, Result<Vec<Vec<f32>>, String>> {
+        Box::pin(async move {
+            let url = format!("{}/embeddings", self.endpoint.trim_end_matches('/'));
+            let body = serde_json::json!({ "model": model.unwrap_or(&self.model), "input": texts });
+            let json = post_json(&url, self.api_key.as_deref(), &body, timeout).await?;
+            Ok(parse_vectors(json["data"].as_array().into_iter().flatten().map(|d| &d["embedding"])))
+        })
+    }
+}
+
+/// Answers in-process without a server. The reply depends only on the
+/// prompt, so the same request always gets the same answer; it is streamed a
+/// word at a time like a real model's.
+pub struct FakeBackend;
+
+impl LlmBackend for FakeBackend {
+    fn stream<'a>(&'a self, request: CompletionRequest, on_token: &'a mut TokenSink<'a>) -> BoxFuture<'a, Result<String, String>> {
+        Box::pin(async move {
+            let last_line = request.prompt.lines().rev().find(|l| !l.trim().is_empty()).unwrap_or("");
+            let reply = format!(
+                "[fake model] {} words received. Last line: {}",
+                request.prompt.split_whitespace().count(),
+                last_line.trim()
+            );
+            for piece in reply.split_inclusive(' ') {
+                on_token(piece);
+            }
+            Ok(reply)
+        })
+    }
+
+    fn list_models(&self) -> BoxFuture<'_, Result<Vec<String>, String>> {
+        Box::pin(async { Ok(vec!["fake".to_string()]) })
+    }
+
+    /// Hashes each word into one of 64 buckets, so texts sharing words are close.
+    fn embed<'a>(&'a self, _model: Option<&'a str>, texts: &'a [String], _timeout: Duration) -> BoxFuture<'a, Result<Vec<Vec<f32>>, String>> {
+        Box::pin(async move {
+            Ok(texts
+                .iter()
+                .map(|text| {
+                    let mut vector = vec![0.0f32; 64];
+                    for word in text.split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty()) {
+                        // FNV-1a, stable across builds unlike the std hasher
+                        let hash = word.to_lowercase().bytes().fold(0xcbf2_9ce4_8422_2325u64, |h, b| (h ^ b as u64).wrapping_mul(0x0100_0000_01b3));
+                        vector[(hash % 64) as usize] += 1.0;
+                    }
+                    vector
+                })
+                .collect())
+        })
+    }
+}
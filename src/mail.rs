@@ -1,50 +1,550 @@
 use imap;
-use native_tls::TlsConnector;
-use crate::auth::get_password;
+use imap::extensions::idle::WaitOutcome;
+use imap::types::{Flag, NameAttribute, UnsolicitedResponse};
+use native_tls::{TlsConnector, TlsStream};
+use lettre::message::header::ContentType;
+use lettre::message::{Mailbox, MultiPart, SinglePart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::client::{SmtpConnection, TlsParameters};
+use lettre::transport::smtp::commands::Ehlo;
+use lettre::transport::smtp::extension::ClientId;
+use lettre::{Message, SmtpTransport, Transport};
+use mailparse::{DispositionType, MailHeaderMap, ParsedMail};
+use regex::Regex;
+use std::collections::HashMap;
+use std::net::{Shutdown, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, LazyLock, Mutex};
+use std::time::Duration;
+use tracing::{debug, warn};
+use crate::auth::{get_password, get_smtp_password, Account};
+use crate::db::{self, DbEmail, DbMailbox};
 
-#[derive(Debug, Clone)]
-pub struct EmailHeader {
-    pub id: u32,
-    pub subject: String,
-    pub from: String,
-    pub date: String,
+type ImapSession = imap::Session<TlsStream<TcpStream>>;
+
+const IDLE_TIMEOUT: Duration = Duration::from_secs(29 * 60);
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+const RECONNECT_DELAY: Duration = Duration::from_secs(30);
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn connect(email: &str, imap_host: &str, imap_port: u16) -> Result<ImapSession, String> {
+    Ok(connect_with_socket(email, imap_host, imap_port)?.0)
+}
+
+/// `connect`, also returning the session's socket so that another thread can
+/// shut it down.
+fn connect_with_socket(email: &str, imap_host: &str, imap_port: u16) -> Result<(ImapSession, TcpStream), String> {
+    let password = get_password(email)?;
+    let (client, socket) = open_client(imap_host, imap_port)?;
+    let session = client.login(email, &password).map_err(|(e, _)| e.to_string())?;
+    Ok((session, socket))
+}
+
+fn login(email: &str, password: &str, imap_host: &str, imap_port: u16) -> Result<ImapSession, String> {
+    let (client, _) = open_client(imap_host, imap_port)?;
+    client.login(email, password).map_err(|(e, _)| e.to_string())
 }
 
-pub fn fetch_inbox(
+fn open_client(imap_host: &str, imap_port: u16) -> Result<(imap::Client<TlsStream<TcpStream>>, TcpStream), String> {
+    let tls = TlsConnector::builder().build().map_err(|e| e.to_string())?;
+    let socket = TcpStream::connect((imap_host, imap_port)).map_err(|e| e.to_string())?;
+    let handle = socket.try_clone().map_err(|e| e.to_string())?;
+    let stream = tls.connect(imap_host, socket).map_err(|e| e.to_string())?;
+    let mut client = imap::Client::new(stream);
+    client.read_greeting().map_err(|e| e.to_string())?;
+    Ok((client, handle))
+}
+
+/// Verifies credentials before an account is saved by logging in and out once.
+pub fn test_login(email: &str, password: &str, imap_host: &str, imap_port: u16) -> Result<(), String> {
+    let mut session = login(email, password, imap_host, imap_port)?;
+    session.logout().map_err(|e| e.to_string())
+}
+
+/// Incrementally syncs `mailbox` into SQLite using UIDs: only messages above the
+/// stored highest UID are downloaded, flags of known messages are refreshed and
+/// expunged messages are removed. A UIDVALIDITY change discards the local copy.
+/// Returns the number of newly stored messages.
+pub fn sync_mailbox(
     email: &str,
     imap_host: &str,
     imap_port: u16,
-) -> Result<Vec<EmailHeader>, String> {
-    let password = get_password(email)?;
-    
-    let tls = TlsConnector::builder().build().map_err(|e| e.to_string())?;
-    let client = imap::connect((imap_host, imap_port), imap_host, &tls)
+    mailbox: &str,
+) -> Result<usize, String> {
+    let mut session = connect(email, imap_host, imap_port)?;
+    let new_count = sync_selected(&mut session, email, mailbox)?;
+    session.logout().map_err(|e| e.to_string())?;
+    Ok(new_count)
+}
+
+/// Refreshes the folder list from IMAP LIST and syncs every selectable folder
+/// except archives over a single connection. Returns the total number of newly
+/// stored messages.
+pub fn sync_all_mailboxes(
+    email: &str,
+    imap_host: &str,
+    imap_port: u16,
+) -> Result<usize, String> {
+    let mut session = connect(email, imap_host, imap_port)?;
+    let mailboxes = list_in_session(&mut session)?;
+    db::save_mailboxes(email, &mailboxes).map_err(|e| e.to_string())?;
+
+    // Archive folders such as Gmail's All Mail repeat every other folder, so
+    // they are only synced when opened
+    let mut new_count = 0;
+    for mailbox in mailboxes.iter().filter(|m| m.selectable && m.special_use.as_deref() != Some("Archive")) {
+        match sync_selected(&mut session, email, &mailbox.name) {
+            Ok(count) => new_count += count,
+            Err(e) => warn!("Failed to sync {} for {}: {}", mailbox.name, email, e),
+        }
+    }
+
+    session.logout().map_err(|e| e.to_string())?;
+    Ok(new_count)
+}
+
+fn list_in_session(session: &mut ImapSession) -> Result<Vec<DbMailbox>, String> {
+    let names = session.list(Some(""), Some("*")).map_err(|e| e.to_string())?;
+    let mut mailboxes: Vec<DbMailbox> = names
+        .iter()
+        .map(|n| {
+            let attributes = n.attributes();
+            DbMailbox {
+                name: n.name().to_string(),
+                delimiter: n.delimiter().map(str::to_string),
+                special_use: special_use(n.name(), attributes),
+                selectable: !attributes.iter().any(|a| matches!(a, NameAttribute::NoSelect)),
+            }
+        })
+        .collect();
+    mailboxes.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(mailboxes)
+}
+
+/// Resolves the RFC 6154 SPECIAL-USE role of a folder, falling back to
+/// well-known folder names for servers that do not advertise it.
+fn special_use(name: &str, attributes: &[NameAttribute]) -> Option<String> {
+    const ROLES: [&str; 5] = ["Sent", "Drafts", "Trash", "Archive", "Junk"];
+    for attribute in attributes {
+        if let NameAttribute::Custom(flag) = attribute {
+            if let Some(role) = ROLES.iter().find(|r| flag.eq_ignore_ascii_case(&format!("\\{}", r))) {
+                return Some(role.to_string());
+            }
+        }
+    }
+
+    if name.eq_ignore_ascii_case("INBOX") {
+        return None;
+    }
+    let leaf = name.rsplit(['/', '.']).next().unwrap_or(name).to_lowercase();
+    let role = match leaf.as_str() {
+        "sent" | "sent items" | "sent mail" | "sent messages" => "Sent",
+        "drafts" | "draft" => "Drafts",
+        "trash" | "deleted items" | "deleted messages" | "bin" => "Trash",
+        "archive" | "archives" | "all mail" => "Archive",
+        "junk" | "spam" | "junk e-mail" | "bulk mail" => "Junk",
+        _ => return None,
+    };
+    Some(role.to_string())
+}
+
+fn sync_selected(session: &mut ImapSession, account: &str, mailbox: &str) -> Result<usize, String> {
+    let selected = session.select(mailbox).map_err(|e| e.to_string())?;
+    let uid_validity = selected.uid_validity.unwrap_or(0);
+
+    let mut highest_uid = match db::get_sync_state(account, mailbox).map_err(|e| e.to_string())? {
+        Some((known_validity, highest)) if known_validity == uid_validity => highest,
+        Some(_) => {
+            warn!("UIDVALIDITY changed for {} ({}), discarding local copy", mailbox, account);
+            db::clear_mailbox(account, mailbox).map_err(|e| e.to_string())?;
+            0
+        }
+        None => 0,
+    };
+
+    if selected.exists == 0 {
+        db::reconcile_mailbox(account, mailbox, &[]).map_err(|e| e.to_string())?;
+        db::set_sync_state(account, mailbox, uid_validity, highest_uid).map_err(|e| e.to_string())?;
+        return Ok(0);
+    }
+
+    // Flags of everything we already have; UIDs missing from the response were expunged
+    if highest_uid > 0 {
+        let known = session
+            .uid_fetch(format!("1:{}", highest_uid), "(UID FLAGS)")
+            .map_err(|e| e.to_string())?;
+        let flags: Vec<(u32, bool)> = known
+            .iter()
+            .filter_map(|m| m.uid.map(|uid| (uid, is_seen(m.flags()))))
+            .collect();
+        let expunged = db::reconcile_mailbox(account, mailbox, &flags).map_err(|e| e.to_string())?;
+        if expunged > 0 {
+            debug!("Removed {} expunged messages from {}", expunged, mailbox);
+        }
+    }
+
+    // `n:*` always matches the newest message, so filter out anything we already have
+    let messages = session
+        .uid_fetch(format!("{}:*", highest_uid + 1), "(UID FLAGS BODY.PEEK[])")
         .map_err(|e| e.to_string())?;
+    let mut emails = Vec::new();
+    for m in messages.iter() {
+        let (Some(uid), Some(raw)) = (m.uid, m.body()) else {
+            continue;
+        };
+        if uid <= highest_uid {
+            continue;
+        }
+        match parse_message(uid, raw) {
+            Ok(mut parsed) => {
+                parsed.is_read = is_seen(m.flags());
+                emails.push(parsed);
+            }
+            Err(e) => warn!("Skipping unparseable message UID {}: {}", uid, e),
+        }
+    }
+
+    db::upsert_emails(account, mailbox, &emails).map_err(|e| e.to_string())?;
+    highest_uid = emails.iter().filter_map(|e| e.uid).fold(highest_uid, u32::max);
+    db::set_sync_state(account, mailbox, uid_validity, highest_uid).map_err(|e| e.to_string())?;
+    debug!("Stored {} new messages from {} for {}", emails.len(), mailbox, account);
+    Ok(emails.len())
+}
+
+/// Stops a mail watcher from another thread. Stopping shuts down the
+/// watcher's connection, so a blocking IDLE returns at once, and wakes it from
+/// any wait between polls or reconnects.
+#[derive(Clone, Default)]
+pub struct WatchStop {
+    state: Arc<(Mutex<StopState>, Condvar)>,
+}
+
+#[derive(Default)]
+struct StopState {
+    stopped: bool,
+    socket: Option<TcpStream>,
+}
+
+impl WatchStop {
+    pub fn stop(&self) {
+        let (state, wake) = &*self.state;
+        let mut state = state.lock().unwrap();
+        state.stopped = true;
+        if let Some(socket) = state.socket.take() {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+        wake.notify_all();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.state.0.lock().unwrap().stopped
+    }
+
+    /// Remembers the connection to shut down on `stop`; shuts it down right
+    /// away if the watcher was stopped while connecting.
+    fn attach(&self, socket: TcpStream) {
+        let mut state = self.state.0.lock().unwrap();
+        if state.stopped {
+            let _ = socket.shutdown(Shutdown::Both);
+        } else {
+            state.socket = Some(socket);
+        }
+    }
+
+    /// Sleeps for `duration` or until stopped.
+    fn sleep(&self, duration: Duration) {
+        let (state, wake) = &*self.state;
+        let state = state.lock().unwrap();
+        let _ = wake.wait_timeout_while(state, duration, |state| !state.stopped);
+    }
+}
+
+/// Blocks until `stop` is stopped, calling `on_change` whenever the server
+/// reports activity in `mailbox`. Uses IMAP IDLE when the server advertises
+/// it and falls back to NOOP polling otherwise. Dropped connections are
+/// retried after a delay. A stopped watcher never calls `on_change` again.
+pub fn watch_mailbox<F: FnMut()>(
+    email: &str,
+    imap_host: &str,
+    imap_port: u16,
+    mailbox: &str,
+    stop: &WatchStop,
+    mut on_change: F,
+) {
+    while !stop.is_stopped() {
+        if let Err(e) = watch_session(email, imap_host, imap_port, mailbox, stop, &mut on_change) {
+            if stop.is_stopped() {
+                break;
+            }
+            warn!("Mail watcher for {} disconnected: {}", email, e);
+            stop.sleep(RECONNECT_DELAY);
+        }
+    }
+    debug!("Stopped watching {} for {}", mailbox, email);
+}
+
+fn watch_session<F: FnMut()>(
+    email: &str,
+    imap_host: &str,
+    imap_port: u16,
+    mailbox: &str,
+    stop: &WatchStop,
+    on_change: &mut F,
+) -> Result<(), String> {
+    let (mut session, socket) = connect_with_socket(email, imap_host, imap_port)?;
+    stop.attach(socket);
+    let supports_idle = session
+        .capabilities()
+        .map_err(|e| e.to_string())?
+        .has_str("IDLE");
+    session.select(mailbox).map_err(|e| e.to_string())?;
+    debug!("Watching {} for {} (IDLE: {})", mailbox, email, supports_idle);
 
-    let mut session = client.login(email, &password).map_err(|(e, _)| e.to_string())?;
-    session.select("INBOX").map_err(|e| e.to_string())?;
-
-    let messages = session.fetch("1:10", "RFC822.SIZE ENVELOPE").map_err(|e| e.to_string())?;
-    
-    let mut headers = Vec::new();
-    for m in &messages {
-        if let Some(envelope) = m.envelope() {
-            headers.push(EmailHeader {
-                id: m.message,
-                subject: String::from_utf8_lossy(envelope.subject.as_deref().unwrap_or(&[])).into_owned(),
-                from: envelope.from.as_ref()
-                    .and_then(|f| f.first())
-                    .map(|addr| {
-                        let mailbox = String::from_utf8_lossy(addr.mailbox.as_deref().unwrap_or(&[])).into_owned();
-                        let host = String::from_utf8_lossy(addr.host.as_deref().unwrap_or(&[])).into_owned();
-                        format!("{}@{}", mailbox, host)
-                    })
-                    .unwrap_or_default(),
-                date: envelope.date.as_ref().map(|d| String::from_utf8_lossy(d).into_owned()).unwrap_or_default(),
+    // Catch up on anything that arrived while we were disconnected
+    if !stop.is_stopped() {
+        on_change();
+    }
+
+    while !stop.is_stopped() {
+        if supports_idle {
+            let outcome = session
+                .idle()
+                .map_err(|e| e.to_string())?
+                .wait_with_timeout(IDLE_TIMEOUT)
+                .map_err(|e| e.to_string())?;
+            // Servers drop IDLE after ~30 minutes, so a timeout just means re-issue it
+            if let WaitOutcome::MailboxChanged = outcome {
+                if !stop.is_stopped() {
+                    on_change();
+                }
+            }
+        } else {
+            stop.sleep(POLL_INTERVAL);
+            if stop.is_stopped() {
+                break;
+            }
+            session.noop().map_err(|e| e.to_string())?;
+            let changed = session.unsolicited_responses.try_iter().any(|r| {
+                matches!(
+                    r,
+                    UnsolicitedResponse::Exists(_)
+                        | UnsolicitedResponse::Expunge(_)
+                        | UnsolicitedResponse::Recent(_)
+                )
             });
+            if changed && !stop.is_stopped() {
+                on_change();
+            }
         }
     }
+    let _ = session.logout();
+    Ok(())
+}
 
-    session.logout().map_err(|e| e.to_string())?;
-    Ok(headers)
+fn is_seen(flags: &[Flag]) -> bool {
+    flags.iter().any(|f| matches!(f, Flag::Seen))
+}
+
+/// Parses a raw RFC822 message into a `DbEmail`, preferring the text/plain
+/// part and falling back to a tag-stripped text/html part. The row id is
+/// assigned by SQLite on insert.
+pub fn parse_message(uid: u32, raw: &[u8]) -> Result<DbEmail, String> {
+    let parsed = mailparse::parse_mail(raw).map_err(|e| e.to_string())?;
+    let header = |name: &str| parsed.headers.get_first_value(name).unwrap_or_default();
+
+    let body = find_body(&parsed, "text/plain")
+        .or_else(|| find_body(&parsed, "text/html").map(|html| html_to_text(&html)))
+        .unwrap_or_default();
+
+    Ok(DbEmail {
+        id: 0,
+        subject: header("Subject"),
+        sender: header("From"),
+        date: header("Date"),
+        body,
+        has_attachment: has_attachment(&parsed),
+        label_ids: Vec::new(),
+        uid: Some(uid),
+        is_read: false,
+        account: String::new(),
+        recipients: [header("To"), header("Cc")]
+            .into_iter()
+            .filter(|r| !r.is_empty())
+            .collect::<Vec<_>>()
+            .join(", "),
+        received_at: mailparse::dateparse(&header("Date")).ok(),
+        size: raw.len() as i64,
+        snippet: None,
+    })
+}
+
+fn is_attachment(part: &ParsedMail) -> bool {
+    let disposition = part.get_content_disposition();
+    disposition.disposition == DispositionType::Attachment
+        || disposition.params.contains_key("filename")
+        || part.ctype.params.contains_key("name")
+}
+
+fn find_body(part: &ParsedMail, mimetype: &str) -> Option<String> {
+    if part.subparts.is_empty() {
+        if part.ctype.mimetype == mimetype && !is_attachment(part) {
+            return part.get_body().ok();
+        }
+        return None;
+    }
+    part.subparts.iter().find_map(|sub| find_body(sub, mimetype))
+}
+
+fn has_attachment(part: &ParsedMail) -> bool {
+    if part.subparts.is_empty() {
+        return is_attachment(part);
+    }
+    part.subparts.iter().any(has_attachment)
+}
+
+static HTML_BLOCKS: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?is)<(script|style)[^>]*>.*?</(script|style)>").unwrap());
+static HTML_BREAKS: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)<br\s*/?>|</p>|</div>|</tr>|</h[1-6]>").unwrap());
+static HTML_TAGS: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?s)<[^>]+>").unwrap());
+static BLANK_LINES: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\n\s*\n\s*\n+").unwrap());
+
+fn html_to_text(html: &str) -> String {
+    let text = HTML_BLOCKS.replace_all(html, "");
+    let text = HTML_BREAKS.replace_all(&text, "\n");
+    let text = HTML_TAGS.replace_all(&text, "");
+    let text = text
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&");
+    BLANK_LINES.replace_all(text.trim(), "\n\n").into_owned()
+}
+
+/// Delivers a message through the account's SMTP server. Port 465 uses
+/// implicit TLS, any other port is upgraded with STARTTLS. With attachments the
+/// message is sent as multipart/mixed; filenames are RFC 2231 encoded by lettre.
+pub fn send_email(
+    account: &Account,
+    to: &str,
+    cc: &str,
+    bcc: &str,
+    subject: &str,
+    body: &str,
+    attachments: &[PathBuf],
+) -> Result<(), String> {
+    if account.smtp_host.trim().is_empty() {
+        return Err(format!("No SMTP server configured for {}", account.email));
+    }
+
+    let parse = |addr: &str| addr.trim().parse::<Mailbox>().map_err(|e| format!("Invalid address '{}': {}", addr, e));
+    let mut builder = Message::builder()
+        .from(parse(&account.email)?)
+        .to(parse(to)?)
+        .subject(subject);
+    if !cc.trim().is_empty() {
+        builder = builder.cc(parse(cc)?);
+    }
+    if !bcc.trim().is_empty() {
+        builder = builder.bcc(parse(bcc)?);
+    }
+    let message = if attachments.is_empty() {
+        builder
+            .header(ContentType::TEXT_PLAIN)
+            .body(body.to_string())
+            .map_err(|e| e.to_string())?
+    } else {
+        let mut parts = MultiPart::mixed().singlepart(SinglePart::plain(body.to_string()));
+        for path in attachments {
+            let content = std::fs::read(path)
+                .map_err(|e| format!("Could not read attachment {}: {}", path.display(), e))?;
+            let filename = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+            let mime = mime_guess::from_path(path).first_or_octet_stream();
+            let content_type = ContentType::parse(mime.essence_str())
+                .unwrap_or_else(|_| ContentType::parse("application/octet-stream").unwrap());
+            parts = parts.singlepart(lettre::message::Attachment::new(filename).body(content, content_type));
+        }
+        builder.multipart(parts).map_err(|e| e.to_string())?
+    };
+
+    let username = account.smtp_username.clone().unwrap_or_else(|| account.email.clone());
+    let password = match get_smtp_password(&account.email)? {
+        Some(password) => password,
+        None => get_password(&account.email)?,
+    };
+
+    let transport = if account.smtp_port == 465 {
+        SmtpTransport::relay(&account.smtp_host)
+    } else {
+        SmtpTransport::starttls_relay(&account.smtp_host)
+    }
+    .map_err(|e| e.to_string())?
+    .port(account.smtp_port)
+    .credentials(Credentials::new(username, password))
+    .timeout(Some(SMTP_TIMEOUT))
+    .build();
+
+    transport.send(&message).map_err(|e| format!("SMTP error: {}", e))?;
+    debug!("Sent '{}' to {} via {}", subject, to, account.smtp_host);
+    Ok(())
+}
+
+/// SIZE limits already read, by SMTP host and port. `None` is cached for
+/// servers that answered without advertising one.
+static SMTP_SIZE_LIMITS: LazyLock<Mutex<HashMap<(String, u16), Option<u64>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Largest message the account's SMTP server accepts, from the SIZE keyword of
+/// its EHLO response (RFC 1870). The server is asked once per host and port.
+/// `None` when the server does not advertise it or cannot be reached; callers
+/// should treat this only as a hint.
+pub fn smtp_size_limit(account: &Account) -> Option<u64> {
+    let key = (account.smtp_host.clone(), account.smtp_port);
+    if let Some(limit) = SMTP_SIZE_LIMITS.lock().unwrap().get(&key) {
+        return *limit;
+    }
+    match read_smtp_size_limit(account) {
+        Ok(limit) => {
+            SMTP_SIZE_LIMITS.lock().unwrap().insert(key, limit);
+            limit
+        }
+        Err(e) => {
+            debug!("Could not read the SMTP size limit of {}: {}", account.smtp_host, e);
+            None
+        }
+    }
+}
+
+/// Reads SIZE from the EHLO response sent once the connection is encrypted,
+/// since servers may advertise different extensions before STARTTLS.
+fn read_smtp_size_limit(account: &Account) -> Result<Option<u64>, String> {
+    let hello = ClientId::default();
+    let tls = TlsParameters::new(account.smtp_host.clone()).map_err(|e| e.to_string())?;
+    let implicit_tls = if account.smtp_port == 465 { Some(&tls) } else { None };
+    let mut conn = SmtpConnection::connect(
+        (account.smtp_host.as_str(), account.smtp_port),
+        Some(SMTP_TIMEOUT),
+        &hello,
+        implicit_tls,
+        None,
+    )
+    .map_err(|e| e.to_string())?;
+    if implicit_tls.is_none() && conn.can_starttls() {
+        conn.starttls(&tls, &hello).map_err(|e| e.to_string())?;
+    }
+
+    let response = conn.command(Ehlo::new(hello));
+    let _ = conn.quit();
+    Ok(response
+        .map_err(|e| e.to_string())?
+        .message()
+        .find_map(|line| line.strip_prefix("SIZE ").and_then(|size| size.trim().parse().ok()))
+        .filter(|size| *size > 0))
+}
+
+/// Rough size of a file once base64 encoded into a MIME part.
+pub fn encoded_size(bytes: u64) -> u64 {
+    bytes.div_ceil(3) * 4 + bytes / 57 * 2
 }
@@ -2,18 +2,33 @@ mod auth;
 mod mail;
 mod ai;
 mod db;
+mod llm;
+mod email_list;
+mod migrations;
+mod profile;
+mod retrieval;
+mod search;
 
 slint::include_modules!();
 
+use slint::winit_030::winit::event::WindowEvent;
+use slint::winit_030::{EventResult, WinitWindowAccessor};
 use slint::{Model, ModelRc, VecModel};
-use chrono::Timelike;
+use chrono::{DateTime, Timelike};
+use email_list::EmailListModel;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::path::PathBuf;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::runtime::Runtime;
 
 use rand::RngExt;
 use email_address::EmailAddress;
 use std::env;
-use tracing::{info, error, warn};
+use tracing::{info, error};
 use tracing_subscriber;
 
 fn sanitize_for_prompt(text: &str) -> String {
@@ -27,182 +42,1102 @@ fn sanitize_for_prompt(text: &str) -> String {
         .replace("###", " # ")
 }
 
+/// Shows `reply` as the assistant's message at `row` of a chat transcript,
+/// adding the row when it is the next one. Returns the replacement model in
+/// that case; a row past the end means the chat was cleared and is ignored.
+fn put_chat_reply(history: &ModelRc<ChatMessage>, row: usize, reply: &str) -> Option<ModelRc<ChatMessage>> {
+    let message = ChatMessage { is_user: false, text: reply.into() };
+    if row < history.row_count() {
+        history.set_row_data(row, message);
+        None
+    } else if row == history.row_count() {
+        // Transcripts built here are VecModels and grow in place
+        if let Some(rows) = history.as_any().downcast_ref::<VecModel<ChatMessage>>() {
+            rows.push(message);
+            return None;
+        }
+        let mut rows: Vec<ChatMessage> = history.iter().collect();
+        rows.push(message);
+        Some(ModelRc::from(Rc::new(VecModel::from(rows))))
+    } else {
+        None
+    }
+}
+
+/// Text streamed so far and whether an update showing it is queued.
+#[derive(Default)]
+struct StreamedText {
+    text: String,
+    update_queued: bool,
+}
+
+/// An `on_token` callback that collects streamed text and passes it to `show`
+/// on the UI thread. At most one update is queued at a time and it shows all
+/// the text received by the time it runs, so a fast model cannot flood the
+/// event loop.
+fn coalesced_tokens<F>(show: F) -> impl FnMut(&str) + Send
+where
+    F: Fn(&str) + Send + Sync + 'static,
+{
+    let streamed = Arc::new(Mutex::new(StreamedText::default()));
+    let show = Arc::new(show);
+    move |piece: &str| {
+        let mut state = streamed.lock().unwrap();
+        state.text.push_str(piece);
+        if state.update_queued {
+            return;
+        }
+        state.update_queued = true;
+        drop(state);
+
+        let (streamed, show) = (streamed.clone(), show.clone());
+        let _ = slint::invoke_from_event_loop(move || {
+            let text = {
+                let mut state = streamed.lock().unwrap();
+                state.update_queued = false;
+                state.text.clone()
+            };
+            show(&text);
+        });
+    }
+}
+
+fn mailboxes_to_model(mailboxes: Vec<db::DbMailbox>) -> ModelRc<Mailbox> {
+    let folders: Vec<Mailbox> = mailboxes
+        .into_iter()
+        .map(|m| {
+            let (label, depth) = match m.delimiter.as_deref() {
+                Some(delim) if !delim.is_empty() => (
+                    m.name.rsplit(delim).next().unwrap_or(&m.name).to_string(),
+                    m.name.matches(delim).count() as i32,
+                ),
+                _ => (m.name.clone(), 0),
+            };
+            Mailbox {
+                label: if m.name.eq_ignore_ascii_case("INBOX") { "Inbox".into() } else { label.into() },
+                name: m.name.into(),
+                role: m.special_use.unwrap_or_default().into(),
+                depth,
+            }
+        })
+        .collect();
+    ModelRc::from(Rc::new(VecModel::from(folders)))
+}
+
+/// Colors offered for labels, in the order "change color" cycles through.
+const LABEL_COLORS: [&str; 8] = ["#0078d4", "#107c10", "#8764b8", "#ca5010", "#038387", "#c239b3", "#d13438", "#986f0b"];
+
+/// Trims a label name and rejects ones that are empty, too long or would be
+/// ambiguous in the categorizer's comma-separated answers.
+fn clean_label_name(name: &str) -> Result<String, String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("Enter a label name.".into());
+    }
+    if name.chars().count() > 40 {
+        return Err("Label names can be at most 40 characters.".into());
+    }
+    if name.contains(',') || name.chars().any(char::is_control) {
+        return Err("Label names cannot contain commas.".into());
+    }
+    Ok(name.to_string())
+}
+
+fn label_error_message(e: rusqlite::Error, name: &str) -> String {
+    match e {
+        rusqlite::Error::SqliteFailure(err, _) if err.code == rusqlite::ErrorCode::ConstraintViolation => {
+            format!("A label named \"{}\" already exists.", name)
+        }
+        e => format!("DB error: {}", e),
+    }
+}
+
+fn load_labels(ui: &AppWindow) {
+    match db::get_labels() {
+        Ok(labels) => {
+            let rows: Vec<Label> = labels.iter().map(email_list::label_row).collect();
+            ui.set_labels(ModelRc::from(Rc::new(VecModel::from(rows))));
+        }
+        Err(e) => ui.set_status_message(format!("DB error: {}", e).into()),
+    }
+}
+
+/// Reloads the label list, the open email's label toggles and the email list
+/// (whose rows show label chips) after labels changed.
+fn refresh_labels(ui: &AppWindow, rt: &tokio::runtime::Handle) {
+    load_labels(ui);
+    let applied = db::get_email_label_ids(ui.get_active_email_id()).unwrap_or_default();
+    show_active_email_labels(ui, &applied);
+    resort_email_list(rt, ui.as_weak());
+    // Smart folders may filter by label
+    refresh_saved_searches(rt, ui.as_weak());
+}
+
+/// Reloads the smart folders with their unread counts for the active account, off the UI thread.
+fn refresh_saved_searches(rt: &tokio::runtime::Handle, ui_handle: slint::Weak<AppWindow>) {
+    let Some(account) = ui_handle.upgrade().map(|ui| ui.get_active_account().to_string()) else {
+        return;
+    };
+    rt.spawn(async move {
+        let result = db::call(move || {
+            db::get_saved_searches()?
+                .into_iter()
+                .map(|search| {
+                    let list = db::EmailList::Search { account: account.clone(), query: search.query.clone() };
+                    Ok((search, db::count_unread(&list)?))
+                })
+                .collect::<rusqlite::Result<Vec<_>>>()
+        })
+        .await;
+        let _ = slint::invoke_from_event_loop(move || {
+            let Some(ui) = ui_handle.upgrade() else {
+                return;
+            };
+            if ui.get_is_locked() {
+                return;
+            }
+            match result {
+                Ok(searches) => {
+                    let rows: Vec<SavedSearch> = searches
+                        .into_iter()
+                        .map(|(search, unread)| SavedSearch {
+                            id: search.id as i32,
+                            name: search.name.into(),
+                            query: search.query.into(),
+                            unread: unread as i32,
+                        })
+                        .collect();
+                    ui.set_saved_searches(ModelRc::from(Rc::new(VecModel::from(rows))));
+                }
+                Err(e) => ui.set_status_message(format!("DB error: {}", e).into()),
+            }
+        });
+    });
+}
+
+/// Offers every label for the open email, marking the ones it has.
+fn show_active_email_labels(ui: &AppWindow, applied: &[i64]) {
+    let toggles: Vec<LabelToggle> = if ui.get_active_email_id() < 0 {
+        Vec::new()
+    } else {
+        ui.get_labels()
+            .iter()
+            .map(|label| LabelToggle { applied: applied.contains(&(label.id as i64)), label })
+            .collect()
+    };
+    ui.set_active_email_labels(ModelRc::from(Rc::new(VecModel::from(toggles))));
+}
+
+fn format_size(bytes: u64) -> String {
+    if bytes < 1024 {
+        format!("{} B", bytes)
+    } else if bytes < 1024 * 1024 {
+        format!("{:.1} KB", bytes as f64 / 1024.0)
+    } else {
+        format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))
+    }
+}
+
+/// Upper bound for the combined attachment size, `MAX_ATTACHMENT_MB` (default 25).
+fn max_attachment_bytes() -> u64 {
+    env::var("MAX_ATTACHMENT_MB")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(25)
+        * 1024
+        * 1024
+}
+
+/// Checks attachments against the configured limit and, as a hint, the SMTP
+/// server's advertised SIZE. Plain-text messages skip the server probe.
+fn check_message_size(account: &auth::Account, attachment_bytes: u64, body_bytes: u64) -> Result<(), String> {
+    if attachment_bytes == 0 {
+        return Ok(());
+    }
+    let encoded = mail::encoded_size(attachment_bytes) + body_bytes;
+    let mut limit = mail::encoded_size(max_attachment_bytes()) + body_bytes;
+    // The server's advertised SIZE only tightens the limit, it never raises it
+    if let Some(server_limit) = mail::smtp_size_limit(account) {
+        limit = limit.min(server_limit);
+    }
+    if encoded > limit {
+        return Err(format!(
+            "Message is about {} after encoding, but the limit is {}.",
+            format_size(encoded),
+            format_size(limit)
+        ));
+    }
+    Ok(())
+}
+
+/// Lists the real (non-demo) accounts in the sidebar switcher.
+fn refresh_account_list(ui: &AppWindow) {
+    let emails: Vec<slint::SharedString> = auth::get_accounts()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|a| !a.is_demo)
+        .map(|a| a.email.into())
+        .collect();
+    ui.set_accounts(ModelRc::from(Rc::new(VecModel::from(emails))));
+}
+
+fn reset_compose(ui: &AppWindow) {
+    ui.set_show_compose_dialog(false);
+    ui.set_compose_error("".into());
+    ui.set_compose_warning("".into());
+    ui.set_force_send(false);
+    ui.set_compose_to("".into());
+    ui.set_compose_cc("".into());
+    ui.set_compose_bcc("".into());
+    ui.set_show_cc_bcc(false);
+    ui.set_compose_subject("".into());
+    ui.set_compose_body("".into());
+
+    // clear attachments
+    let empty: Vec<Attachment> = Vec::new();
+    ui.set_compose_attachments(ModelRc::from(Rc::new(VecModel::from(empty))));
+}
+
+/// Keeps a push connection for `account`: after a one-off refresh of all folders,
+/// IDLE (or NOOP polling) on INBOX triggers an incremental sync, after which the
+/// list is refreshed from SQLite.
+/// Keeps at most one background pass of a kind running. Requests made while
+/// it runs are folded into a single further pass, so mail stored in the
+/// meantime is still picked up without working on the same rows twice.
+struct SingleFlight {
+    running: AtomicBool,
+    requested: AtomicBool,
+}
+
+impl SingleFlight {
+    const fn new() -> Self {
+        SingleFlight { running: AtomicBool::new(false), requested: AtomicBool::new(false) }
+    }
+
+    fn spawn<F, Fut>(&'static self, rt: &tokio::runtime::Handle, pass: F)
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: std::future::Future<Output = ()> + Send + 'static,
+    {
+        self.requested.store(true, Ordering::SeqCst);
+        if self.running.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        rt.spawn(async move {
+            loop {
+                while self.requested.swap(false, Ordering::SeqCst) {
+                    pass().await;
+                }
+                self.running.store(false, Ordering::SeqCst);
+                // A request made just before `running` was cleared found it still set
+                if !self.requested.load(Ordering::SeqCst) || self.running.swap(true, Ordering::SeqCst) {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+static CATEGORIZING: SingleFlight = SingleFlight::new();
+static EMBEDDING: SingleFlight = SingleFlight::new();
+
+/// Labels and embeds recently stored mail in the background.
+fn start_background_indexing(rt: &tokio::runtime::Handle) {
+    CATEGORIZING.spawn(rt, categorize_new_mail);
+    EMBEDDING.spawn(rt, embed_new_mail);
+}
+
+/// Embeds new mail for chat retrieval.
+async fn embed_new_mail() {
+    let indexed = retrieval::index_embeddings(EMBEDDINGS_PER_FETCH).await;
+    if indexed > 0 {
+        info!("Embedded {} emails for chat retrieval", indexed);
+    }
+}
+
+/// Refreshes whatever list is on screen and the smart folder counts after
+/// new mail was stored for `account`, leaving the user in the folder, label
+/// or search they are viewing.
+fn show_new_mail(ui: &AppWindow, rt: &tokio::runtime::Handle, account: &str) {
+    let active = ui.get_active_account();
+    if ui.get_is_locked() || !(active.is_empty() || active == account) {
+        return;
+    }
+    resort_email_list(rt, ui.as_weak());
+    refresh_saved_searches(rt, ui.as_weak());
+    start_background_indexing(rt);
+}
+
+/// Lets the model pick labels for the newest uncategorized emails.
+async fn categorize_new_mail() {
+    let Ok(labels) = db::call(db::get_labels).await else {
+        return;
+    };
+    if labels.is_empty() {
+        return;
+    }
+    let names: Vec<String> = labels.iter().map(|l| l.name.clone()).collect();
+    if let Ok(emails) = db::call(|| db::get_uncategorized_emails(20)).await {
+        for e in emails { // Just categorize the newest 20 uncategorized for demo
+            if let Ok(chosen) = ai::categorize_email(&e.subject, &e.body, &names).await {
+                let label_ids: Vec<i64> = labels
+                    .iter()
+                    .filter(|l| chosen.contains(&l.name))
+                    .map(|l| l.id)
+                    .collect();
+                let _ = db::call(move || db::apply_categorized_labels(e.id, &label_ids)).await;
+            }
+        }
+    }
+}
+
+thread_local! {
+    /// Stop handles of the running mail watchers, one per account.
+    static MAIL_WATCHERS: RefCell<HashMap<String, mail::WatchStop>> = RefCell::new(HashMap::new());
+}
+
+/// Stops every mail watcher, closing its IMAP connection.
+fn stop_mail_watchers() {
+    MAIL_WATCHERS.with(|watchers| watchers.borrow_mut().drain().for_each(|(_, stop)| stop.stop()));
+}
+
+fn start_account_sync(rt: &tokio::runtime::Handle, ui_handle_sync: slint::Weak<AppWindow>, account: auth::Account) {
+    // Replaces the account's watcher, e.g. after its settings were saved again;
+    // `lock_app` stops it
+    let stop = mail::WatchStop::default();
+    let previous = MAIL_WATCHERS.with(|watchers| watchers.borrow_mut().insert(account.email.clone(), stop.clone()));
+    if let Some(previous) = previous {
+        previous.stop();
+    }
+    let rt_new_mail = rt.clone();
+    rt.spawn_blocking(move || {
+        // Full folder refresh once per start; afterwards only INBOX is pushed
+        match mail::sync_all_mailboxes(&account.email, &account.imap_host, account.imap_port) {
+            Ok(new_count) => {
+                info!("Synced {} new emails across all folders for {}", new_count, account.email);
+                let ui_handle_folders = ui_handle_sync.clone();
+                let account_email = account.email.clone();
+                let _ = slint::invoke_from_event_loop(move || {
+                    if let Some(ui) = ui_handle_folders.upgrade() {
+                        if !ui.get_is_locked() && ui.get_active_account() == account_email.as_str() {
+                            if let Ok(mailboxes) = db::get_mailboxes(&account_email) {
+                                ui.set_mailboxes(mailboxes_to_model(mailboxes));
+                            }
+                        }
+                    }
+                });
+            }
+            Err(e) => error!("Folder sync failed for {}: {}", account.email, e),
+        }
+
+        mail::watch_mailbox(&account.email, &account.imap_host, account.imap_port, "INBOX", &stop, || {
+            match mail::sync_mailbox(&account.email, &account.imap_host, account.imap_port, "INBOX") {
+                Ok(new_count) => {
+                    info!("Synced {} new emails for {}", new_count, account.email);
+                    let ui_handle_sync = ui_handle_sync.clone();
+                    let rt = rt_new_mail.clone();
+                    let account_email = account.email.clone();
+                    let _ = slint::invoke_from_event_loop(move || {
+                        if let Some(ui) = ui_handle_sync.upgrade() {
+                            show_new_mail(&ui, &rt, &account_email);
+                        }
+                    });
+                }
+                Err(e) => error!("IMAP sync failed for {}: {}", account.email, e),
+            }
+        });
+    });
+}
+
+fn start_all_account_syncs(rt: &tokio::runtime::Handle, ui_handle: slint::Weak<AppWindow>) {
+    let accounts = auth::get_accounts().unwrap_or_default();
+    for account in accounts.into_iter().filter(|a| !a.is_demo) {
+        start_account_sync(rt, ui_handle.clone(), account);
+    }
+}
+
+/// Bumped by every request that replaces the email list, so a slow query can
+/// never overwrite the result of one issued after it (e.g. while typing a search).
+static EMAIL_LIST_REQUEST: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// The list on screen, shown again when the sort order changes.
+    static CURRENT_EMAIL_LIST: RefCell<Option<db::EmailList>> = RefCell::new(None);
+}
+
+/// Counts `list` and reads its first page off the UI thread, then shows it in
+/// the selected sort order, unless a newer list request was made in the meantime.
+/// Further pages are read by `EmailListModel` as the list is scrolled.
+fn show_emails_async(
+    rt: &tokio::runtime::Handle,
+    ui_handle: slint::Weak<AppWindow>,
+    loaded_status: Option<&'static str>,
+    list: db::EmailList,
+) {
+    let Some(sort) = ui_handle.upgrade().map(|ui| db::SortOrder::from_name(ui.get_sort_order().as_str())) else {
+        return;
+    };
+    CURRENT_EMAIL_LIST.with(|current| *current.borrow_mut() = Some(list.clone()));
+    let request = EMAIL_LIST_REQUEST.fetch_add(1, Ordering::SeqCst) + 1;
+    let model_rt = rt.clone();
+    rt.spawn(async move {
+        let query_list = list.clone();
+        let result = db::call(move || {
+            let count = db::count_list(&query_list)?;
+            let first_page = db::list_headers(&query_list, sort, &db::PageStart::Offset(0), email_list::PAGE_SIZE)?;
+            Ok((count, first_page, db::get_labels()?))
+        })
+        .await;
+        let _ = slint::invoke_from_event_loop(move || {
+            if EMAIL_LIST_REQUEST.load(Ordering::SeqCst) != request {
+                return;
+            }
+            let Some(ui) = ui_handle.upgrade() else {
+                return;
+            };
+            if ui.get_is_locked() {
+                return;
+            }
+            match result {
+                Ok((count, first_page, labels)) => {
+                    let model = EmailListModel::new(model_rt, list, sort, count, first_page, labels);
+                    ui.set_emails(ModelRc::from(model));
+                    if let Some(status) = loaded_status {
+                        ui.set_status_message(status.into());
+                    }
+                }
+                Err(e) => {
+                    ui.set_status_message(format!("DB error: {}", e).into());
+                }
+            }
+        });
+    });
+}
+
+/// Shows the current email list again in the newly selected order.
+fn resort_email_list(rt: &tokio::runtime::Handle, ui_handle: slint::Weak<AppWindow>) {
+    if let Some(list) = CURRENT_EMAIL_LIST.with(|current| current.borrow().clone()) {
+        show_emails_async(rt, ui_handle, None, list);
+    }
+}
+
+/// Parts of the window an AI request writes into. Each runs at most one
+/// request; starting another stops the one before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum AiTask {
+    Chat,
+    EmailChat,
+    Reply,
+}
+
+/// Emails embedded after each fetch; the rest are picked up by later fetches.
+const EMBEDDINGS_PER_FETCH: usize = 200;
+
+/// Shown in the compose body by "Reply w/ AI" until the first words arrive.
+const AI_DRAFT_PLACEHOLDER: &str = "✨ Drafting AI response...";
+
+static NEXT_AI_TASK_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    /// The running request for each part, with the id its updates carry.
+    static AI_TASKS: RefCell<HashMap<AiTask, (u64, tokio::task::AbortHandle)>> = RefCell::default();
+}
+
+/// Spawns the future `make` builds for `task`, stopping any request already
+/// running for it. The future is given its id; UI updates it sends should
+/// check `is_current_ai_task` so nothing lands once it is stopped or replaced.
+fn spawn_ai_task<F>(rt: &tokio::runtime::Handle, task: AiTask, make: impl FnOnce(u64) -> F)
+where
+    F: std::future::Future<Output = ()> + Send + 'static,
+{
+    let id = NEXT_AI_TASK_ID.fetch_add(1, Ordering::SeqCst);
+    let handle = rt.spawn(make(id));
+    AI_TASKS.with(|tasks| {
+        if let Some((_, previous)) = tasks.borrow_mut().insert(task, (id, handle.abort_handle())) {
+            previous.abort();
+        }
+    });
+}
+
+fn is_current_ai_task(task: AiTask, id: u64) -> bool {
+    AI_TASKS.with(|tasks| tasks.borrow().get(&task).is_some_and(|(current, _)| *current == id))
+}
+
+/// Forgets a request that has delivered its result. Returns false if it was
+/// stopped or replaced first, in which case the result should be dropped.
+fn finish_ai_task(task: AiTask, id: u64) -> bool {
+    AI_TASKS.with(|tasks| {
+        let mut tasks = tasks.borrow_mut();
+        if tasks.get(&task).is_some_and(|(current, _)| *current == id) {
+            tasks.remove(&task);
+            true
+        } else {
+            false
+        }
+    })
+}
+
+/// Aborts the request running for `task`, if any. Returns whether one was.
+fn stop_ai_task(task: AiTask) -> bool {
+    AI_TASKS.with(|tasks| match tasks.borrow_mut().remove(&task) {
+        Some((_, handle)) => {
+            handle.abort();
+            true
+        }
+        None => false,
+    })
+}
+
+/// Opens the AI settings with what is saved, dropping edits from a cancelled visit.
+fn show_llm_settings(ui: &AppWindow) {
+    let settings = llm::LlmSettings::load();
+    ui.set_llm_backend(settings.backend.name().into());
+    ui.set_llm_endpoint(settings.endpoint.as_str().into());
+    ui.set_llm_model(settings.model.as_str().into());
+    let tasks: Vec<TaskConfig> = llm::Task::ALL
+        .into_iter()
+        .map(|task| {
+            let config = settings.task(task);
+            TaskConfig {
+                task: task.name().into(),
+                title: task.title().into(),
+                model: config.model.into(),
+                context: config.context_tokens.map(|t| t.to_string()).unwrap_or_default().into(),
+                temperature: config.temperature.map(|t| t.to_string()).unwrap_or_default().into(),
+                timeout: config.timeout_secs.to_string().into(),
+            }
+        })
+        .collect();
+    ui.set_llm_tasks(ModelRc::from(Rc::new(VecModel::from(tasks))));
+    let no_models: Vec<slint::SharedString> = Vec::new();
+    ui.set_installed_models(ModelRc::from(Rc::new(VecModel::from(no_models))));
+    ui.set_installed_models_status("".into());
+    ui.set_llm_settings_error("".into());
+    ui.set_show_llm_settings_dialog(true);
+}
+
+/// Locks the app: forgets the keys, stops background syncs and drops every
+/// email and chat message held by the UI.
+fn lock_app(ui: &AppWindow) {
+    auth::lock();
+    stop_mail_watchers();
+    stop_ai_task(AiTask::Chat);
+    stop_ai_task(AiTask::EmailChat);
+    stop_ai_task(AiTask::Reply);
+    // Discard list queries that are still in flight
+    EMAIL_LIST_REQUEST.fetch_add(1, Ordering::SeqCst);
+    CURRENT_EMAIL_LIST.with(|current| *current.borrow_mut() = None);
+
+    let no_emails: Vec<Email> = Vec::new();
+    ui.set_emails(ModelRc::from(Rc::new(VecModel::from(no_emails))));
+    let no_messages: Vec<ChatMessage> = Vec::new();
+    ui.set_chat_history(ModelRc::from(Rc::new(VecModel::from(no_messages.clone()))));
+    ui.set_email_chat_history(ModelRc::from(Rc::new(VecModel::from(no_messages))));
+    let no_mailboxes: Vec<db::DbMailbox> = Vec::new();
+    ui.set_mailboxes(mailboxes_to_model(no_mailboxes));
+    let no_labels: Vec<Label> = Vec::new();
+    ui.set_labels(ModelRc::from(Rc::new(VecModel::from(no_labels))));
+    let no_toggles: Vec<LabelToggle> = Vec::new();
+    ui.set_active_email_labels(ModelRc::from(Rc::new(VecModel::from(no_toggles))));
+    ui.set_show_labels_dialog(false);
+    let no_searches: Vec<SavedSearch> = Vec::new();
+    ui.set_saved_searches(ModelRc::from(Rc::new(VecModel::from(no_searches))));
+    ui.set_show_save_search_dialog(false);
+    ui.set_show_llm_settings_dialog(false);
+    llm::reset();
+    let no_accounts: Vec<slint::SharedString> = Vec::new();
+    ui.set_accounts(ModelRc::from(Rc::new(VecModel::from(no_accounts))));
+
+    ui.set_active_email_id(-1);
+    ui.set_active_email_subject("Select an email".into());
+    ui.set_active_email_sender("".into());
+    ui.set_active_email_date("".into());
+    ui.set_active_email_body("Select an email to view its contents.".into());
+    ui.set_show_email_chat_popup(false);
+    ui.set_chat_input("".into());
+    ui.set_email_chat_input("".into());
+    ui.set_search_text("".into());
+    ui.set_chat_loading(false);
+    ui.set_email_chat_loading(false);
+    ui.set_ai_reply_drafting(false);
+    reset_compose(ui);
+
+    ui.set_password_input("".into());
+    ui.set_is_locked(true);
+    ui.set_status_message("Locked".into());
+}
+
+/// Brings the mailbox up once the master password (or recovery key) checks out.
+fn finish_unlock(ui: &AppWindow, rt: &tokio::runtime::Handle, status: &str) {
+    ui.set_has_master_password(true);
+    ui.set_is_locked(false);
+    ui.set_password_input("".into());
+    ui.set_status_message(status.into());
+    load_local_data(ui);
+    start_all_account_syncs(rt, ui.as_weak());
+}
+
+/// Populates the window from SQLite: accounts, folders, saved layout and the
+/// email list. Seeds the demo inbox when there is nothing else to show.
+fn load_local_data(ui: &AppWindow) {
+    ui.set_has_accounts(!auth::get_accounts().unwrap_or_default().is_empty());
+    refresh_account_list(ui);
+    // With a single mailbox account there is nothing to unify; open it directly
+    if let [only] = ui.get_accounts().iter().collect::<Vec<_>>().as_slice() {
+        ui.set_active_account(only.clone());
+    }
+
+    // Real (non-demo) IMAP accounts replace the mock inbox entirely
+    let real_accounts: Vec<auth::Account> = auth::get_accounts()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|a| !a.is_demo)
+        .collect();
+
+    // Generate mock emails ONLY if database is empty and there is nothing to sync
+    match db::count_emails() {
+        Ok(count) if count == 0 && real_accounts.is_empty() => {
+            println!("Database empty. Generating 1000 mock emails...");
+            if let Err(e) = db::insert_emails(&generate_mock_emails()) {
+                eprintln!("Failed to insert into SQLite: {}", e);
+            }
+        },
+        Ok(count) => println!("Found {} emails in SQLite, skipping generation.", count),
+        Err(e) => eprintln!("Error checking database: {}", e),
+    }
+
+    if let Ok(mailboxes) = db::get_mailboxes(ui.get_active_account().as_str()) {
+        ui.set_mailboxes(mailboxes_to_model(mailboxes));
+    }
+    load_labels(ui);
+    if let Ok(width) = db::get_sidebar_width() {
+        ui.set_sidebar_width(width);
+    }
+    if let Ok(mode) = db::get_theme_mode() {
+        ui.set_theme_mode(mode.into());
+    }
+    if let Ok(minutes) = db::get_auto_lock_minutes() {
+        ui.set_auto_lock_minutes(minutes);
+    }
+    if let Ok(sort) = db::get_sort_order() {
+        ui.set_sort_order(sort.name().into());
+    }
+
+    // Trigger initial fetch
+    ui.invoke_fetch_emails();
+}
+
+/// `--encrypt-database`: converts an existing plaintext database to SQLCipher
+/// without starting the UI. The master password is read from stdin.
+#[cfg(feature = "sqlcipher")]
+fn encrypt_database_cli() -> Result<(), String> {
+    if db::database_state() != db::DbState::Plaintext {
+        return Err(format!("There is no plaintext database at {} to convert.", db::database_path().display()));
+    }
+    let hash = db::get_master_password_hash()
+        .map_err(|e| e.to_string())?
+        .ok_or("Set a master password in the app before encrypting the database.")?;
+
+    println!("Master password:");
+    let mut password = String::new();
+    std::io::stdin().read_line(&mut password).map_err(|e| e.to_string())?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    if !bcrypt::verify(password, &hash).unwrap_or(false) {
+        return Err("Incorrect password".into());
+    }
+    db::encrypt_in_place(password).map_err(|e| e.to_string())
+}
+
+fn generate_mock_emails() -> Vec<db::DbEmail> {
+    let mut rng = rand::rng();
+    let mut db_emails = Vec::with_capacity(1000);
+    let now = chrono::Utc::now().timestamp();
+
+    let subjects = ["Project Update", "Invoice #", "Weekly Newsletter", "Meeting Notes", "Q3 Report", "Lunch?", "Action Required"];
+    let senders = ["boss@company.com", "billing@services.io", "newsletter@techly.com", "team@company.com", "friend@email.com"];
+    let bodies = [
+        "Please find the latest project update. We are on track to deliver all features in Q4. This includes the new AI engine and the revamped UI. Let me know if you have any questions.\n\nThe team worked hard on this, so please share your feedback soon.",
+        "Your invoice is due next week. Please review the attached PDF for the breakdown of the charges. If there are any discrepancies, contact billing support immediately.",
+        "Here is the weekly round-up of tech news! Lots of exciting developments in AI this week. We have seen new models released and better performance benchmarks.\n\nRead the full details below.",
+        "Notes from our sync this morning. Key takeaways: we need more velocity on the frontend. Backend APIs look solid but we are lacking test coverage. Everyone, please update your JIRA tickets.",
+        "Just checking if you wanted to grab lunch today? I was thinking about that new place downtown.",
+        "Please review the attached document and provide your sign-off by EOD. This is critical for unblocking the release."
+    ];
+
+    for i in 1..=1000 {
+        let has_attachment = rng.random_bool(0.2);
+        let subject_idx = rng.random_range(0..subjects.len());
+        let sender_idx = rng.random_range(0..senders.len());
+        let body_idx = rng.random_range(0..bodies.len());
+        
+        let mut subject = subjects[subject_idx].to_string();
+        if subject == "Invoice #" {
+            subject = format!("Invoice #{}", rng.random_range(1000..9999));
+        }
+        
+        let mins_ago = rng.random_range(1..60000);
+        let received_at = now - mins_ago as i64 * 60;
+        let date = DateTime::from_timestamp(received_at, 0)
+            .map(|d| d.to_rfc2822())
+            .unwrap_or_default();
+        let body = bodies[body_idx];
+        // Headers plus body, and an encoded attachment when there is one
+        let size = 600 + body.len() as i64 + if has_attachment { rng.random_range(20_000..4_000_000) } else { 0 };
+
+        db_emails.push(db::DbEmail {
+            id: i,
+            subject,
+            sender: senders[sender_idx].into(),
+            date,
+            body: body.into(),
+            has_attachment,
+            label_ids: Vec::new(),
+            uid: None,
+            is_read: false,
+            account: String::new(),
+            recipients: "me@neural-mail.local".into(),
+            received_at: Some(received_at),
+            size,
+            snippet: None,
+        });
+    }
+    db_emails
+}
+
 fn main() -> Result<(), slint::PlatformError> {
     tracing_subscriber::fmt::init();
     dotenvy::dotenv().ok();
     info!("Starting Neural Mail Client...");
+
+    if env::args().any(|arg| arg == "--list-profiles") {
+        for name in profile::list() {
+            println!("{}", name);
+        }
+        return Ok(());
+    }
+    let profile = match profile::select() {
+        Ok(profile) => profile,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    info!("Using profile '{}' in {}", profile.name, profile.dir.display());
+    db::set_database_path(profile.database_path());
+
+    #[cfg(feature = "sqlcipher")]
+    if env::args().any(|arg| arg == "--encrypt-database") {
+        match encrypt_database_cli() {
+            Ok(()) => println!("{} is now encrypted.", db::database_path().display()),
+            Err(e) => {
+                eprintln!("Encryption failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
     let ui = AppWindow::new()?;
+    if profile.name != profile::DEFAULT_PROFILE {
+        ui.set_profile_name(profile.name.clone().into());
+    }
     let rt = Runtime::new().unwrap();
     let rt_handle_fetch = rt.handle().clone();
     let rt_handle_email_chat = rt.handle().clone();
     let rt_handle_chat = rt.handle().clone();
     let rt_handle_reply = rt.handle().clone();
+    let rt_handle_sync = rt.handle().clone();
+    let rt_handle_send = rt.handle().clone();
     
-    // Initialize both DBs (accounts if we ever use them, and emails)
-    let _ = auth::init_db();
-    let _ = db::init_db();
+    // Nothing is read from the mail store until the master password is entered;
+    // an encrypted database cannot even be opened before then
+    if !db::opens_without_key() {
+        match db::database_state() {
+            db::DbState::Encrypted if !cfg!(feature = "sqlcipher") => {
+                error!("{} is encrypted; rebuild with --features sqlcipher to open it", db::database_path().display());
+                ui.set_status_message("Database is encrypted; this build lacks SQLCipher support".into());
+                ui.set_has_master_password(true);
+            }
+            db::DbState::Encrypted => ui.set_has_master_password(true),
+            _ => ui.set_has_master_password(false),
+        }
+    } else {
+        // Bring the schema up to date; a database from a newer build stays closed
+        if let Err(e) = db::init_db() {
+            error!("Cannot open database: {}", e);
+            ui.set_status_message(e.to_string().into());
+        }
 
-    // Check for Master Password
-    match db::get_master_password_hash() {
-        Ok(Some(_)) => ui.set_has_master_password(true),
-        _ => ui.set_has_master_password(false),
+        // Check for Master Password
+        match db::get_master_password_hash() {
+            Ok(Some(_)) => ui.set_has_master_password(true),
+            _ => ui.set_has_master_password(false),
+        }
     }
-    
-    // Generate mock emails ONLY if database is empty
-    match db::count_emails() {
-        Ok(count) if count == 0 => {
-            println!("Database empty. Generating 1000 mock emails...");
-            let mut rng = rand::rng();
-            let mut db_emails = Vec::with_capacity(1000);
-
-            let subjects = ["Project Update", "Invoice #", "Weekly Newsletter", "Meeting Notes", "Q3 Report", "Lunch?", "Action Required"];
-            let senders = ["boss@company.com", "billing@services.io", "newsletter@techly.com", "team@company.com", "friend@email.com"];
-            let bodies = [
-                "Please find the latest project update. We are on track to deliver all features in Q4. This includes the new AI engine and the revamped UI. Let me know if you have any questions.\n\nThe team worked hard on this, so please share your feedback soon.",
-                "Your invoice is due next week. Please review the attached PDF for the breakdown of the charges. If there are any discrepancies, contact billing support immediately.",
-                "Here is the weekly round-up of tech news! Lots of exciting developments in AI this week. We have seen new models released and better performance benchmarks.\n\nRead the full details below.",
-                "Notes from our sync this morning. Key takeaways: we need more velocity on the frontend. Backend APIs look solid but we are lacking test coverage. Everyone, please update your JIRA tickets.",
-                "Just checking if you wanted to grab lunch today? I was thinking about that new place downtown.",
-                "Please review the attached document and provide your sign-off by EOD. This is critical for unblocking the release."
-            ];
-
-            for i in 1..=1000 {
-                let has_attachment = rng.random_bool(0.2);
-                let subject_idx = rng.random_range(0..subjects.len());
-                let sender_idx = rng.random_range(0..senders.len());
-                let body_idx = rng.random_range(0..bodies.len());
-                
-                let mut subject = subjects[subject_idx].to_string();
-                if subject == "Invoice #" {
-                    subject = format!("Invoice #{}", rng.random_range(1000..9999));
-                }
-                
-                let mins_ago = rng.random_range(1..60000);
-                let date = if mins_ago < 60 {
-                    format!("{}m ago", mins_ago)
-                } else if mins_ago < 1440 {
-                    format!("{}h ago", mins_ago / 60)
-                } else {
-                    format!("{}d ago", mins_ago / 1440)
-                };
 
-                db_emails.push(db::DbEmail {
-                    id: i,
-                    subject,
-                    sender: senders[sender_idx].into(),
-                    date,
-                    body: bodies[body_idx].into(),
-                    has_attachment,
-                    category: "Inbox".into(),
-                });
-            }
-            
-            if let Err(e) = db::insert_emails(&db_emails) {
-                eprintln!("Failed to insert into SQLite: {}", e);
-            }
-        },
-        Ok(count) => println!("Found {} emails in SQLite, skipping generation.", count),
-        Err(e) => eprintln!("Error checking database: {}", e),
-    }
-    
     let ui_handle = ui.as_weak();
     ui.on_fetch_emails(move || {
         let ui = ui_handle.unwrap();
-        
-        let mut slint_emails = Vec::new();
-        match db::get_all_emails() {
-            Ok(db_emails) => {
-                for e in db_emails {
-                    slint_emails.push(Email {
-                        id: e.id,
-                        subject: e.subject.into(),
-                        sender: e.sender.into(),
-                        date: e.date.into(),
-                        body: e.body.into(),
-                        has_attachment: e.has_attachment,
-                        category: e.category.into(),
-                    });
-                }
-                let model = Rc::new(VecModel::from(slint_emails));
-                ui.set_emails(ModelRc::from(model));
-                ui.set_status_message("Loaded Emails from DB".into());
-                
-                // Background Categorize Task
-                let rt_bg = rt_handle_fetch.clone();
-                rt_bg.spawn(async move {
-                    if let Ok(emails) = db::get_all_emails() {
-                        for e in emails.iter().take(20) { // Just categorize first 20 for demo
-                            if e.category == "Inbox" {
-                                if let Ok(new_cat) = ai::categorize_email(&e.subject, &e.body).await {
-                                    let _ = db::update_email_category(e.id, &new_cat);
-                                }
-                            }
-                        }
-                    }
-                });
-            },
-            Err(e) => {
-                ui.set_status_message(format!("DB error: {}", e).into());
-            }
+        if ui.get_is_locked() {
+            return;
         }
+
+        let account = ui.get_active_account().to_string();
+        show_emails_async(&rt_handle_fetch, ui_handle.clone(), Some("Loaded Emails from DB"), db::EmailList::Inbox { account });
+        refresh_saved_searches(&rt_handle_fetch, ui_handle.clone());
+
+        start_background_indexing(&rt_handle_fetch);
     });
 
     let ui_handle_cat = ui.as_weak();
-    ui.on_category_changed(move |cat: slint::SharedString| {
+    let rt_handle_cat = rt.handle().clone();
+    ui.on_label_changed(move |label_id| {
         if let Some(ui) = ui_handle_cat.upgrade() {
-            let mut slint_emails = Vec::new();
-            match db::get_emails_by_category(cat.as_str()) {
-                Ok(db_emails) => {
-                    for e in db_emails {
-                        slint_emails.push(Email {
-                            id: e.id,
-                            subject: e.subject.into(),
-                            sender: e.sender.into(),
-                            date: e.date.into(),
-                            body: e.body.into(),
-                            has_attachment: e.has_attachment,
-                            category: e.category.into(),
-                        });
-                    }
-                    ui.set_emails(ModelRc::from(Rc::new(VecModel::from(slint_emails))));
-                }
-                Err(e) => eprintln!("Category error: {}", e),
-            }
+            let account = ui.get_active_account().to_string();
+            let list = db::EmailList::Label { account, label_id: label_id as i64 };
+            show_emails_async(&rt_handle_cat, ui_handle_cat.clone(), None, list);
         }
     });
 
     let ui_handle_search = ui.as_weak();
+    let rt_handle_search = rt.handle().clone();
     ui.on_search_changed(move |query: slint::SharedString| {
         if let Some(ui) = ui_handle_search.upgrade() {
-            let mut slint_emails = Vec::new();
-            match db::search_emails(query.as_str()) {
-                Ok(db_emails) => {
-                    for e in db_emails {
-                        slint_emails.push(Email {
-                            id: e.id,
-                            subject: e.subject.into(),
-                            sender: e.sender.into(),
-                            date: e.date.into(),
-                            body: e.body.into(),
-                            has_attachment: e.has_attachment,
-                            category: e.category.into(),
-                        });
+            let account = ui.get_active_account().to_string();
+            let list = db::EmailList::Search { account, query: query.to_string() };
+            show_emails_async(&rt_handle_search, ui_handle_search.clone(), None, list);
+        }
+    });
+
+    let ui_handle_sort = ui.as_weak();
+    let rt_handle_sort = rt.handle().clone();
+    ui.on_sort_changed(move |order: slint::SharedString| {
+        let sort = db::SortOrder::from_name(order.as_str());
+        if let Err(e) = db::save_sort_order(sort) {
+            eprintln!("Failed to save sort order: {}", e);
+        }
+        resort_email_list(&rt_handle_sort, ui_handle_sort.clone());
+    });
+
+    // List rows carry headers only; read the body of the email being opened
+    let ui_handle_open = ui.as_weak();
+    let rt_handle_open = rt.handle().clone();
+    ui.on_open_email(move |id| {
+        let ui_handle = ui_handle_open.clone();
+        rt_handle_open.spawn(async move {
+            let result = db::call(move || Ok((db::get_email_body(id)?, db::get_email_label_ids(id)?))).await;
+            let _ = slint::invoke_from_event_loop(move || {
+                let Some(ui) = ui_handle.upgrade() else {
+                    return;
+                };
+                // Locked, or another email was opened meanwhile
+                if ui.get_is_locked() || ui.get_active_email_id() != id {
+                    return;
+                }
+                match result {
+                    Ok((Some(body), label_ids)) => {
+                        ui.set_active_email_body(body.into());
+                        show_active_email_labels(&ui, &label_ids);
                     }
-                    ui.set_emails(ModelRc::from(Rc::new(VecModel::from(slint_emails))));
+                    Ok((None, _)) => ui.set_active_email_body("This email is no longer available.".into()),
+                    Err(e) => ui.set_status_message(format!("DB error: {}", e).into()),
                 }
-                Err(e) => eprintln!("Search error: {}", e),
+            });
+        });
+    });
+    
+    let ui_handle_save_search = ui.as_weak();
+    let rt_handle_save_search = rt.handle().clone();
+    ui.on_save_search(move |name, query| {
+        let Some(ui) = ui_handle_save_search.upgrade() else {
+            return;
+        };
+        let (name, query) = (name.trim().to_string(), query.trim().to_string());
+        if name.is_empty() || query.is_empty() {
+            ui.set_saved_search_error("Enter a name and a search to save.".into());
+            return;
+        }
+        match db::create_saved_search(&name, &query) {
+            Ok(_) => {
+                ui.set_show_save_search_dialog(false);
+                refresh_saved_searches(&rt_handle_save_search, ui.as_weak());
+            }
+            Err(rusqlite::Error::SqliteFailure(err, _)) if err.code == rusqlite::ErrorCode::ConstraintViolation => {
+                ui.set_saved_search_error(format!("A smart folder named \"{}\" already exists.", name).into());
             }
+            Err(e) => ui.set_saved_search_error(format!("DB error: {}", e).into()),
+        }
+    });
+
+    let ui_handle_delete_search = ui.as_weak();
+    let rt_handle_delete_search = rt.handle().clone();
+    ui.on_delete_saved_search(move |id| {
+        let Some(ui) = ui_handle_delete_search.upgrade() else {
+            return;
+        };
+        match db::delete_saved_search(id as i64) {
+            Ok(()) => refresh_saved_searches(&rt_handle_delete_search, ui.as_weak()),
+            Err(e) => ui.set_status_message(format!("DB error: {}", e).into()),
+        }
+    });
+
+    let ui_handle_toggle_label = ui.as_weak();
+    let rt_handle_toggle_label = rt.handle().clone();
+    ui.on_toggle_email_label(move |label_id, apply| {
+        let Some(ui) = ui_handle_toggle_label.upgrade() else {
+            return;
+        };
+        let email_id = ui.get_active_email_id();
+        if email_id < 0 {
+            return;
+        }
+        match db::set_email_label(email_id, label_id as i64, apply) {
+            Ok(()) => refresh_labels(&ui, &rt_handle_toggle_label),
+            Err(e) => ui.set_status_message(format!("DB error: {}", e).into()),
         }
     });
-    
-    // Trigger initial fetch
-    ui.invoke_fetch_emails();
+
+    let ui_handle_create_label = ui.as_weak();
+    let rt_handle_create_label = rt.handle().clone();
+    ui.on_create_label(move |name| {
+        let Some(ui) = ui_handle_create_label.upgrade() else {
+            return;
+        };
+        let name = match clean_label_name(&name) {
+            Ok(name) => name,
+            Err(e) => {
+                ui.set_label_error(e.into());
+                return;
+            }
+        };
+        let color = LABEL_COLORS[ui.get_labels().row_count() % LABEL_COLORS.len()];
+        match db::create_label(&name, color) {
+            Ok(_) => {
+                ui.set_label_error("".into());
+                ui.set_new_label_name("".into());
+                refresh_labels(&ui, &rt_handle_create_label);
+            }
+            Err(e) => ui.set_label_error(label_error_message(e, &name).into()),
+        }
+    });
+
+    let ui_handle_rename_label = ui.as_weak();
+    let rt_handle_rename_label = rt.handle().clone();
+    ui.on_rename_label(move |id, name| {
+        let Some(ui) = ui_handle_rename_label.upgrade() else {
+            return;
+        };
+        let result = clean_label_name(&name)
+            .and_then(|name| db::rename_label(id as i64, &name).map_err(|e| label_error_message(e, &name)));
+        match result {
+            Ok(()) => ui.set_label_error("".into()),
+            Err(e) => ui.set_label_error(e.into()),
+        }
+        // Also puts the old name back into the field after a failed rename
+        refresh_labels(&ui, &rt_handle_rename_label);
+    });
+
+    let ui_handle_label_color = ui.as_weak();
+    let rt_handle_label_color = rt.handle().clone();
+    ui.on_cycle_label_color(move |id| {
+        let Some(ui) = ui_handle_label_color.upgrade() else {
+            return;
+        };
+        let current = db::get_labels()
+            .unwrap_or_default()
+            .into_iter()
+            .find(|l| l.id == id as i64)
+            .map(|l| l.color)
+            .unwrap_or_default();
+        let next = LABEL_COLORS
+            .iter()
+            .position(|c| c.eq_ignore_ascii_case(&current))
+            .map_or(0, |i| (i + 1) % LABEL_COLORS.len());
+        match db::set_label_color(id as i64, LABEL_COLORS[next]) {
+            Ok(()) => refresh_labels(&ui, &rt_handle_label_color),
+            Err(e) => ui.set_label_error(format!("DB error: {}", e).into()),
+        }
+    });
+
+    let ui_handle_delete_label = ui.as_weak();
+    let rt_handle_delete_label = rt.handle().clone();
+    ui.on_delete_label(move |id| {
+        let Some(ui) = ui_handle_delete_label.upgrade() else {
+            return;
+        };
+        match db::delete_label(id as i64) {
+            Ok(()) => {
+                // The list on screen may be the deleted label's
+                if ui.get_active_label() == id {
+                    ui.set_active_label(-1);
+                    ui.invoke_fetch_emails();
+                }
+                refresh_labels(&ui, &rt_handle_delete_label);
+            }
+            Err(e) => ui.set_label_error(format!("DB error: {}", e).into()),
+        }
+    });
+
+    // Switching accounts swaps the folder tree; "" is the unified inbox across all accounts
+    let ui_handle_account_switch = ui.as_weak();
+    ui.on_account_changed(move |account: slint::SharedString| {
+        if let Some(ui) = ui_handle_account_switch.upgrade() {
+            match db::get_mailboxes(account.as_str()) {
+                Ok(mailboxes) => ui.set_mailboxes(mailboxes_to_model(mailboxes)),
+                Err(e) => eprintln!("Mailbox list error: {}", e),
+            }
+            ui.set_active_mailbox("".into());
+            ui.set_active_label(-1);
+            ui.invoke_fetch_emails();
+        }
+    });
+
+    // Show the local copy of a folder immediately, then pull anything new from the server
+    let ui_handle_mailbox = ui.as_weak();
+    let rt_handle_mailbox = rt.handle().clone();
+    ui.on_mailbox_changed(move |mailbox: slint::SharedString| {
+        let Some(ui) = ui_handle_mailbox.upgrade() else {
+            return;
+        };
+        if ui.get_is_locked() {
+            return;
+        }
+        let account_email = ui.get_active_account().to_string();
+        let list = db::EmailList::Mailbox { account: account_email.clone(), mailbox: mailbox.to_string() };
+        show_emails_async(&rt_handle_mailbox, ui_handle_mailbox.clone(), None, list);
+
+        let accounts = auth::get_accounts().unwrap_or_default();
+        let Some(account) = accounts.into_iter().find(|a| !a.is_demo && a.email == account_email) else {
+            return;
+        };
+        let ui_handle_async = ui_handle_mailbox.clone();
+        let mailbox = mailbox.to_string();
+        rt_handle_mailbox.spawn_blocking(move || {
+            match mail::sync_mailbox(&account.email, &account.imap_host, account.imap_port, &mailbox) {
+                Ok(0) => {}
+                Ok(_) => {
+                    let _ = slint::invoke_from_event_loop(move || {
+                        if let Some(ui) = ui_handle_async.upgrade() {
+                            if ui.get_active_account() == account.email.as_str()
+                                && ui.get_active_mailbox() == mailbox.as_str()
+                            {
+                                ui.invoke_mailbox_changed(mailbox.into());
+                            }
+                        }
+                    });
+                }
+                Err(e) => error!("Failed to sync {} for {}: {}", mailbox, account.email, e),
+            }
+        });
+    });
+
+    // Account syncs start once the master password has unlocked the stored credentials
 
     // -- Contextual Single Email Chat Backend Handle --
     let ui_handle_email_chat = ui.as_weak();
     ui.on_send_email_chat_message(move |msg| {
         let ui = ui_handle_email_chat.unwrap();
-        ui.set_loading(true);
+        ui.set_email_chat_loading(true);
         
         let msg_clone = msg.to_string();
         let ui_for_async = ui_handle_email_chat.clone();
@@ -216,34 +1151,44 @@ fn main() -> Result<(), slint::PlatformError> {
             is_user: true,
             text: msg_clone.clone().into(),
         });
+        let reply_row = history.len();
         ui.set_email_chat_history(ModelRc::from(Rc::new(VecModel::from(history))));
         ui.set_email_chat_input("".into());
 
-        rt_handle_email_chat.spawn(async move {
+        spawn_ai_task(&rt_handle_email_chat, AiTask::EmailChat, |id| async move {
             let s_sender = sanitize_for_prompt(&sender);
             let s_subject = sanitize_for_prompt(&subject);
             let s_body = sanitize_for_prompt(&body);
             let s_msg = sanitize_for_prompt(&msg_clone);
 
             let context_str = format!("From: {}\nSubject: {}\nBody: {}\n", s_sender, s_subject, s_body);
-            
-            let result = ai::chat_with_emails(&s_msg, &context_str).await;
+
+            let ui_for_tokens = ui_for_async.clone();
+            let mut on_token = coalesced_tokens(move |text: &str| {
+                if let Some(ui) = ui_for_tokens.upgrade() {
+                    if is_current_ai_task(AiTask::EmailChat, id) {
+                        if let Some(history) = put_chat_reply(&ui.get_email_chat_history(), reply_row, text) {
+                            ui.set_email_chat_history(history);
+                        }
+                    }
+                }
+            });
+            let result = ai::chat_with_emails(&s_msg, &context_str, &mut on_token).await;
             
             slint::invoke_from_event_loop(move || {
                 if let Some(ui) = ui_for_async.upgrade() {
-                    ui.set_loading(false);
-                    let mut history: Vec<ChatMessage> = ui.get_email_chat_history().iter().collect();
-                    match result {
-                        Ok(reply) => history.push(ChatMessage {
-                            is_user: false,
-                            text: reply.into(),
-                        }),
-                        Err(e) => history.push(ChatMessage {
-                            is_user: false,
-                            text: format!("Error: {}", e).into(),
-                        }),
+                    // Stopped, or the email (or the lock) wiped the conversation
+                    if !finish_ai_task(AiTask::EmailChat, id) {
+                        return;
+                    }
+                    ui.set_email_chat_loading(false);
+                    let reply = match result {
+                        Ok(reply) => reply,
+                        Err(e) => format!("Error: {}", e),
+                    };
+                    if let Some(history) = put_chat_reply(&ui.get_email_chat_history(), reply_row, &reply) {
+                        ui.set_email_chat_history(history);
                     }
-                    ui.set_email_chat_history(ModelRc::from(Rc::new(VecModel::from(history))));
                 }
             }).unwrap();
         });
@@ -252,10 +1197,11 @@ fn main() -> Result<(), slint::PlatformError> {
     let ui_handle_chat = ui.as_weak();
     ui.on_send_chat_message(move |msg| {
         let ui = ui_handle_chat.unwrap();
-        ui.set_loading(true);
+        ui.set_chat_loading(true);
         
         let msg_clone = msg.to_string();
         let ui_for_async = ui_handle_chat.clone();
+        let account = ui.get_active_account().to_string();
         
         // Push user's message immediately
         let mut history: Vec<ChatMessage> = ui.get_chat_history().iter().collect();
@@ -263,6 +1209,7 @@ fn main() -> Result<(), slint::PlatformError> {
             is_user: true,
             text: msg_clone.clone().into(),
         });
+        let reply_row = history.len();
         ui.set_chat_history(ModelRc::from(Rc::new(VecModel::from(history))));
         ui.set_chat_input("".into());
 
@@ -288,41 +1235,39 @@ fn main() -> Result<(), slint::PlatformError> {
                 text: reply.into(),
             });
             ui.set_chat_history(ModelRc::from(Rc::new(VecModel::from(history))));
-            ui.set_loading(false);
+            ui.set_chat_loading(false);
             return;
         }
 
-        rt_handle_chat.spawn(async move {
-            let mut context_str = String::new();
-            if let Ok(emails) = db::get_all_emails() {
-                for e in emails.iter().take(20) {
-                    let s_sender = sanitize_for_prompt(&e.sender);
-                    let s_subject = sanitize_for_prompt(&e.subject);
-                    let body_truncated = if e.body.len() > 400 { format!("{}...", &e.body[..400]) } else { e.body.clone() };
-                    let s_body = sanitize_for_prompt(&body_truncated);
-                    context_str.push_str(&format!("From: {}, Subject: {}\nBody: {}\n\n", s_sender, s_subject, s_body));
-                }
-            } else {
-                context_str = "No emails found in SQLite database.".to_string();
-            }
+        spawn_ai_task(&rt_handle_chat, AiTask::Chat, |id| async move {
+            let context_str = retrieval::context_for_question(&msg_clone, &account).await;
 
-            let result = ai::chat_with_emails(&msg_clone, &context_str).await;
+            let ui_for_tokens = ui_for_async.clone();
+            let mut on_token = coalesced_tokens(move |text: &str| {
+                if let Some(ui) = ui_for_tokens.upgrade() {
+                    if is_current_ai_task(AiTask::Chat, id) {
+                        if let Some(history) = put_chat_reply(&ui.get_chat_history(), reply_row, text) {
+                            ui.set_chat_history(history);
+                        }
+                    }
+                }
+            });
+            let result = ai::chat_with_emails(&msg_clone, &context_str, &mut on_token).await;
             
             slint::invoke_from_event_loop(move || {
                 if let Some(ui) = ui_for_async.upgrade() {
-                    ui.set_loading(false);
-                    let mut history: Vec<ChatMessage> = ui.get_chat_history().iter().collect();
-                    match result {
-                        Ok(reply) => history.push(ChatMessage {
-                            is_user: false,
-                            text: reply.into(),
-                        }),
-                        Err(e) => history.push(ChatMessage {
-                            is_user: false,
-                            text: format!("Error: {}", e).into(),
-                        }),
+                    // Stopped, or the conversation was wiped by a lock
+                    if !finish_ai_task(AiTask::Chat, id) {
+                        return;
+                    }
+                    ui.set_chat_loading(false);
+                    let reply = match result {
+                        Ok(reply) => reply,
+                        Err(e) => format!("Error: {}", e),
+                    };
+                    if let Some(history) = put_chat_reply(&ui.get_chat_history(), reply_row, &reply) {
+                        ui.set_chat_history(history);
                     }
-                    ui.set_chat_history(ModelRc::from(Rc::new(VecModel::from(history))));
                 }
             }).unwrap();
         });
@@ -335,19 +1280,11 @@ fn main() -> Result<(), slint::PlatformError> {
         }
     });
 
-    if let Ok(width) = db::get_sidebar_width() {
-        ui.set_sidebar_width(width);
-    }
-    
     ui.on_save_theme_mode(move |mode| {
         if let Err(e) = db::save_theme_mode(mode.as_str()) {
             error!("Failed to save theme mode: {}", e);
         }
     });
-    
-    if let Ok(mode) = db::get_theme_mode() {
-        ui.set_theme_mode(mode.into());
-    }
 
     let ui_handle_send = ui.as_weak();
     ui.on_send_email(move |to, cc, bcc, subject, body, attachments, force_send| {
@@ -433,31 +1370,74 @@ fn main() -> Result<(), slint::PlatformError> {
             info!("SUBJECT: {}", subject);
             info!("ATTACHMENTS: {} files", attachments.row_count());
             for i in 0..attachments.row_count() {
-                info!(" - {}", attachments.row_data(i).unwrap());
+                let attachment = attachments.row_data(i).unwrap();
+                info!(" - {} ({})", attachment.path, attachment.size_label);
             }
             info!("------------- BODY --------------");
             info!("{}", body);
             info!("=================================");
-        } else {
-            // Future: Implement real secure SMTP here
-            warn!("Production Mode: Real email sending not yet implemented.");
+            if let Some(ui) = ui_handle_send.upgrade() {
+                reset_compose(&ui);
+            }
+            return;
         }
+
+        // Send from the account being viewed; the unified view falls back to the first account
+        let active_account = ui_handle_send.upgrade().map(|ui| ui.get_active_account().to_string()).unwrap_or_default();
+        let real_accounts: Vec<auth::Account> = auth::get_accounts()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|a| !a.is_demo)
+            .collect();
+        let account = real_accounts
+            .iter()
+            .find(|a| a.email == active_account)
+            .or(real_accounts.first())
+            .cloned();
+        let Some(account) = account else {
+            if let Some(ui) = ui_handle_send.upgrade() {
+                ui.set_compose_error("No email account is configured for sending.".into());
+            }
+            return;
+        };
+
         if let Some(ui) = ui_handle_send.upgrade() {
-            ui.set_show_compose_dialog(false);
             ui.set_compose_error("".into());
             ui.set_compose_warning("".into());
-            ui.set_force_send(false);
-            ui.set_compose_to("".into());
-            ui.set_compose_cc("".into());
-            ui.set_compose_bcc("".into());
-            ui.set_show_cc_bcc(false);
-            ui.set_compose_subject("".into());
-            ui.set_compose_body("".into());
-            
-            // clear attachments
-            let empty: Vec<slint::SharedString> = Vec::new();
-            ui.set_compose_attachments(ModelRc::from(Rc::new(VecModel::from(empty))));
+            ui.set_status_message("Sending...".into());
         }
+
+        let ui_handle_async = ui_handle_send.clone();
+        let subject_str = subject.to_string();
+        let attachment_paths: Vec<PathBuf> = attachments.iter().map(|a| PathBuf::from(a.path.as_str())).collect();
+        let attachment_bytes: u64 = attachments.iter().map(|a| a.size as u64).sum();
+        rt_handle_send.spawn_blocking(move || {
+            let result = check_message_size(&account, attachment_bytes, body_str.len() as u64)
+                .and_then(|_| mail::send_email(
+                    &account,
+                    &to_str,
+                    &cc_str,
+                    &bcc_str,
+                    &subject_str,
+                    &body_str,
+                    &attachment_paths,
+                ));
+            let _ = slint::invoke_from_event_loop(move || {
+                if let Some(ui) = ui_handle_async.upgrade() {
+                    match result {
+                        Ok(()) => {
+                            ui.set_status_message("Email sent".into());
+                            reset_compose(&ui);
+                        }
+                        Err(e) => {
+                            error!("Failed to send email: {}", e);
+                            ui.set_status_message("".into());
+                            ui.set_compose_error(e.into());
+                        }
+                    }
+                }
+            });
+        });
     });
 
     let ui_handle_attachments = ui.as_weak();
@@ -471,9 +1451,33 @@ fn main() -> Result<(), slint::PlatformError> {
                     .unwrap_or_default()
                     .to_string_lossy()
                     .to_string();
+                let size = match std::fs::metadata(&file_path) {
+                    Ok(meta) => meta.len(),
+                    Err(e) => {
+                        ui.set_compose_error(format!("Could not read {}: {}", filename, e).into());
+                        return;
+                    }
+                };
                 
-                let mut current: Vec<slint::SharedString> = ui.get_compose_attachments().iter().collect();
-                current.push(filename.into());
+                let mut current: Vec<Attachment> = ui.get_compose_attachments().iter().collect();
+                let total: u64 = current.iter().map(|a| a.size as u64).sum::<u64>() + size;
+                let limit = max_attachment_bytes();
+                if total > limit {
+                    ui.set_compose_error(format!(
+                        "Attachments would total {}, over the {} limit.",
+                        format_size(total),
+                        format_size(limit)
+                    ).into());
+                    return;
+                }
+
+                current.push(Attachment {
+                    path: file_path.to_string_lossy().to_string().into(),
+                    name: filename.into(),
+                    size: size.min(i32::MAX as u64) as i32,
+                    size_label: format_size(size).into(),
+                });
+                ui.set_compose_error("".into());
                 ui.set_compose_attachments(ModelRc::from(Rc::new(VecModel::from(current))));
             }
         }
@@ -482,7 +1486,7 @@ fn main() -> Result<(), slint::PlatformError> {
     let ui_handle_remove = ui.as_weak();
     ui.on_remove_attachment(move |idx| {
         if let Some(ui) = ui_handle_remove.upgrade() {
-            let mut current: Vec<slint::SharedString> = ui.get_compose_attachments().iter().collect();
+            let mut current: Vec<Attachment> = ui.get_compose_attachments().iter().collect();
             if (idx as usize) < current.len() {
                 current.remove(idx as usize);
                 ui.set_compose_attachments(ModelRc::from(Rc::new(VecModel::from(current))));
@@ -493,66 +1497,371 @@ fn main() -> Result<(), slint::PlatformError> {
     let ui_handle_ai_reply = ui.as_weak();
     ui.on_generate_ai_reply(move |sender, subject, original_body| {
         let ui_handle_async = ui_handle_ai_reply.clone();
+        if let Some(ui) = ui_handle_ai_reply.upgrade() {
+            ui.set_ai_reply_drafting(true);
+        }
         let sender_clone = sender.to_string();
         let subject_clone = subject.to_string();
         let original_body_clone = original_body.to_string();
         
-        rt_handle_reply.spawn(async move {
-            let result = ai::generate_reply(&original_body_clone).await;
+        // The full compose body with the AI's draft on top of the quoted original;
+        // `done` marks the final text, which ends the request
+        let quoted = format!("\n\n--- Original Message ---\nFrom: {}\nSubject: {}\n\n{}", sender_clone, subject_clone, original_body_clone);
+        let show_draft = |ui: &AppWindow, id: u64, draft: &str, quoted: &str, done: bool| {
+            // Stopped, replaced, or the compose pane was closed or reused
+            let current = if done { finish_ai_task(AiTask::Reply, id) } else { is_current_ai_task(AiTask::Reply, id) };
+            if !current {
+                return;
+            }
+            if done {
+                ui.set_ai_reply_drafting(false);
+            }
+            ui.set_compose_body(format!("{}{}", draft, quoted).into());
+        };
+
+        spawn_ai_task(&rt_handle_reply, AiTask::Reply, |id| async move {
+            let ui_for_tokens = ui_handle_async.clone();
+            let quoted_for_tokens = quoted.clone();
+            let mut on_token = coalesced_tokens(move |partial: &str| {
+                if let Some(ui) = ui_for_tokens.upgrade() {
+                    show_draft(&ui, id, partial, &quoted_for_tokens, false);
+                }
+            });
+            let result = ai::generate_reply(&original_body_clone, &mut on_token).await;
             
             let final_reply = match result {
                 Ok(reply) => reply,
                 Err(e) => format!("Error generating reply: {}", e),
             };
-            
-            // Reconstruct the full compose body with the AI's draft on top
-            let reconstructed_body = format!(
-                "{}\n\n--- Original Message ---\nFrom: {}\nSubject: {}\n\n{}", 
-                final_reply, sender_clone, subject_clone, original_body_clone
-            );
-            
             let _ = slint::invoke_from_event_loop(move || {
                 if let Some(ui) = ui_handle_async.upgrade() {
-                    ui.set_compose_body(reconstructed_body.into());
+                    show_draft(&ui, id, &final_reply, &quoted, true);
+                }
+            });
+        });
+    });
+
+    let ui_handle_stop_reply = ui.as_weak();
+    ui.on_stop_ai_reply(move || {
+        let ui = ui_handle_stop_reply.unwrap();
+        if stop_ai_task(AiTask::Reply) {
+            // Nothing was written yet; leave just the quoted original
+            let body = ui.get_compose_body();
+            if let Some(rest) = body.strip_prefix(AI_DRAFT_PLACEHOLDER) {
+                ui.set_compose_body(rest.into());
+            }
+        }
+        ui.set_ai_reply_drafting(false);
+    });
+
+    let ui_handle_stop_chat = ui.as_weak();
+    ui.on_stop_chat(move || {
+        stop_ai_task(AiTask::Chat);
+        ui_handle_stop_chat.unwrap().set_chat_loading(false);
+    });
+
+    let ui_handle_stop_email_chat = ui.as_weak();
+    ui.on_stop_email_chat(move || {
+        stop_ai_task(AiTask::EmailChat);
+        ui_handle_stop_email_chat.unwrap().set_email_chat_loading(false);
+    });
+
+    let ui_handle_account = ui.as_weak();
+    let rt_handle_account = rt.handle().clone();
+    ui.on_save_account(move |email, imap_host, password, imap_port, smtp_host, smtp_port, is_demo| {
+        let ui = ui_handle_account.unwrap();
+        let email = email.trim().to_string();
+
+        if is_demo {
+            let account = auth::Account {
+                email: if email.is_empty() { "demo@neural-mail.local".to_string() } else { email },
+                imap_host: String::new(),
+                imap_port: 0,
+                password: None,
+                is_demo: true,
+                smtp_host: String::new(),
+                smtp_port: 0,
+                smtp_username: None,
+                smtp_password: None,
+            };
+            if let Err(e) = auth::save_account(&account) {
+                ui.set_account_error(format!("Failed to save account: {}", e).into());
+                return;
+            }
+            // Demo accounts never touch the network; they run on the generated inbox
+            if db::count_emails().unwrap_or(0) == 0 {
+                if let Err(e) = db::insert_emails(&generate_mock_emails()) {
+                    error!("Failed to insert into SQLite: {}", e);
+                }
+            }
+            ui.set_has_accounts(true);
+            ui.set_show_account_dialog(false);
+            ui.set_account_error("".into());
+            ui.invoke_fetch_emails();
+            return;
+        }
+
+        if !EmailAddress::is_valid(&email) {
+            ui.set_account_error("Please enter a valid email address.".into());
+            return;
+        }
+        if imap_host.trim().is_empty() || smtp_host.trim().is_empty() {
+            ui.set_account_error("IMAP and SMTP hosts are required.".into());
+            return;
+        }
+        let (Ok(imap_port), Ok(smtp_port)) = (imap_port.trim().parse::<u16>(), smtp_port.trim().parse::<u16>()) else {
+            ui.set_account_error("Ports must be numbers between 1 and 65535.".into());
+            return;
+        };
+        if password.is_empty() {
+            ui.set_account_error("Please enter your password.".into());
+            return;
+        }
+
+        let account = auth::Account {
+            email,
+            imap_host: imap_host.trim().to_string(),
+            imap_port,
+            password: Some(password.to_string()),
+            is_demo: false,
+            smtp_host: smtp_host.trim().to_string(),
+            smtp_port,
+            smtp_username: None,
+            smtp_password: None,
+        };
+
+        ui.set_account_error("".into());
+        ui.set_account_testing(true);
+
+        let ui_handle_async = ui_handle_account.clone();
+        let rt_handle_sync = rt_handle_account.clone();
+        rt_handle_account.spawn_blocking(move || {
+            let result = mail::test_login(
+                &account.email,
+                account.password.as_deref().unwrap_or_default(),
+                &account.imap_host,
+                account.imap_port,
+            )
+            .and_then(|_| auth::save_account(&account));
+
+            let _ = slint::invoke_from_event_loop(move || {
+                if let Some(ui) = ui_handle_async.upgrade() {
+                    ui.set_account_testing(false);
+                    match result {
+                        Ok(()) => {
+                            info!("Account {} verified and saved", account.email);
+                            // Real mail replaces the generated demo inbox
+                            if let Err(e) = db::clear_mock_emails() {
+                                error!("Failed to clear demo emails: {}", e);
+                            }
+                            ui.set_has_accounts(true);
+                            refresh_account_list(&ui);
+                            ui.set_show_account_dialog(false);
+                            ui.set_new_password("".into());
+                            ui.invoke_fetch_emails();
+                            start_account_sync(&rt_handle_sync, ui.as_weak(), account);
+                        }
+                        Err(e) => {
+                            ui.set_account_error(format!("Could not sign in: {}", e).into());
+                        }
+                    }
                 }
             });
         });
     });
 
     let ui_handle_auth = ui.as_weak();
+    let rt_handle_auth = rt_handle_sync.clone();
     ui.on_create_master_password(move |password| {
         let ui = ui_handle_auth.unwrap();
-        let pass_str = password.to_string();
-        if pass_str.len() < 4 {
-            ui.set_status_message("Password too short (min 4 chars)".into());
-            return;
-        }
-        
-        let hash = bcrypt::hash(pass_str, bcrypt::DEFAULT_COST).unwrap();
-        if let Ok(_) = db::set_master_password(&hash) {
-            ui.set_has_master_password(true);
-            ui.set_is_locked(false);
-            ui.set_password_input("".into());
-            ui.set_status_message("Master password set successfully".into());
+        match auth::setup_master_password(password.as_str()) {
+            Ok(recovery_key) => {
+                finish_unlock(&ui, &rt_handle_auth, "Master password set successfully");
+                ui.set_recovery_key(recovery_key.into());
+            }
+            Err(e) => ui.set_status_message(e.into()),
         }
     });
 
     let ui_handle_verify = ui.as_weak();
+    let rt_handle_verify = rt_handle_sync.clone();
     ui.on_verify_password(move |password| {
         let ui = ui_handle_verify.unwrap();
-        match db::get_master_password_hash() {
-            Ok(Some(hash)) => {
-                if bcrypt::verify(password.to_string(), &hash).unwrap_or(false) {
-                    ui.set_is_locked(false);
-                    ui.set_password_input("".into());
-                    ui.set_status_message("Unlocked".into());
-                } else {
-                    ui.set_status_message("Incorrect password".into());
+        match auth::unlock_with_master_password(password.as_str()) {
+            Ok(()) => finish_unlock(&ui, &rt_handle_verify, "Unlocked"),
+            Err(e) => {
+                ui.set_password_input("".into());
+                ui.set_status_message(e.into());
+            }
+        }
+    });
+
+    let ui_handle_recover = ui.as_weak();
+    let rt_handle_recover = rt_handle_sync.clone();
+    ui.on_recover_with_key(move |recovery_key, new_password, confirm_password| {
+        let ui = ui_handle_recover.unwrap();
+        if new_password != confirm_password {
+            ui.set_status_message("The new passwords do not match.".into());
+            return;
+        }
+        match auth::recover_with_key(recovery_key.as_str(), new_password.as_str()) {
+            Ok(new_recovery_key) => {
+                ui.set_show_recovery_form(false);
+                ui.set_recovery_key_input("".into());
+                ui.set_new_master_password("".into());
+                ui.set_confirm_master_password("".into());
+                finish_unlock(&ui, &rt_handle_recover, "Master password reset");
+                ui.set_recovery_key(new_recovery_key.into());
+            }
+            Err(e) => ui.set_status_message(e.into()),
+        }
+    });
+
+    let ui_handle_change = ui.as_weak();
+    ui.on_change_master_password(move |current_password, new_password, confirm_password| {
+        let ui = ui_handle_change.unwrap();
+        if new_password != confirm_password {
+            ui.set_password_change_error("The new passwords do not match.".into());
+            return;
+        }
+        match auth::change_master_password(current_password.as_str(), new_password.as_str()) {
+            Ok(recovery_key) => {
+                ui.set_show_change_password_dialog(false);
+                ui.set_password_change_error("".into());
+                ui.set_current_master_password("".into());
+                ui.set_new_master_password("".into());
+                ui.set_confirm_master_password("".into());
+                ui.set_recovery_key(recovery_key.into());
+            }
+            Err(e) => ui.set_password_change_error(e.into()),
+        }
+    });
+
+    let ui_handle_open_llm = ui.as_weak();
+    ui.on_open_llm_settings(move || {
+        show_llm_settings(&ui_handle_open_llm.unwrap());
+    });
+
+    let ui_handle_llm = ui.as_weak();
+    ui.on_save_llm_settings(move |backend, endpoint, model, tasks| {
+        let ui = ui_handle_llm.unwrap();
+        let mut settings = llm::LlmSettings {
+            backend: llm::BackendKind::from_name(backend.as_str()),
+            endpoint: endpoint.trim().to_string(),
+            model: model.trim().to_string(),
+            tasks: HashMap::new(),
+        };
+        for row in tasks.iter() {
+            let Some(task) = llm::Task::from_name(row.task.as_str()) else {
+                continue;
+            };
+            match llm::TaskSettings::parse(&row.model, &row.context, &row.temperature, &row.timeout) {
+                Ok(task_settings) => {
+                    settings.tasks.insert(task, task_settings);
                 }
+                Err(e) => {
+                    ui.set_llm_settings_error(format!("{}: {}", task.title(), e).into());
+                    return;
+                }
+            }
+        }
+        match llm::configure(settings) {
+            Ok(()) => {
+                ui.set_show_llm_settings_dialog(false);
+                ui.set_llm_settings_error("".into());
+                ui.set_status_message("AI settings saved".into());
             }
-            _ => ui.set_status_message("No master password set".into()),
+            Err(e) => ui.set_llm_settings_error(e.into()),
+        }
+    });
+
+    let ui_handle_models = ui.as_weak();
+    let rt_handle_models = rt.handle().clone();
+    ui.on_list_installed_models(move |backend, endpoint| {
+        let ui = ui_handle_models.unwrap();
+        ui.set_installed_models_status("Asking the server…".into());
+        let settings = llm::LlmSettings {
+            backend: llm::BackendKind::from_name(backend.as_str()),
+            endpoint: endpoint.trim().to_string(),
+            ..llm::LlmSettings::default()
+        };
+        let ui_handle_async = ui_handle_models.clone();
+        rt_handle_models.spawn(async move {
+            let result = llm::list_models(&settings).await;
+            let _ = slint::invoke_from_event_loop(move || {
+                let Some(ui) = ui_handle_async.upgrade() else {
+                    return;
+                };
+                let models = match result {
+                    Ok(models) => {
+                        ui.set_installed_models_status(match models.len() {
+                            0 => "The server has no models installed.".into(),
+                            n => format!("{} models installed; click one to make it the default.", n).into(),
+                        });
+                        models
+                    }
+                    Err(e) => {
+                        ui.set_installed_models_status(e.into());
+                        Vec::new()
+                    }
+                };
+                let models: Vec<slint::SharedString> = models.into_iter().map(Into::into).collect();
+                ui.set_installed_models(ModelRc::from(Rc::new(VecModel::from(models))));
+            });
+        });
+    });
+
+    let ui_handle_lock = ui.as_weak();
+    ui.on_lock_now(move || {
+        if let Some(ui) = ui_handle_lock.upgrade() {
+            lock_app(&ui);
+        }
+    });
+
+    ui.on_save_auto_lock_minutes(move |minutes| {
+        if let Err(e) = db::save_auto_lock_minutes(minutes) {
+            error!("Failed to save auto-lock setting: {}", e);
+        }
+    });
+
+    // Only real input counts as activity: keys, clicks, scrolling and pointer
+    // movement, not text the app writes into the window such as a streamed draft
+    let last_activity = Rc::new(Cell::new(Instant::now()));
+    let last_activity_input = last_activity.clone();
+    ui.window().on_winit_window_event(move |_, event| {
+        if matches!(
+            event,
+            WindowEvent::KeyboardInput { .. }
+                | WindowEvent::MouseInput { .. }
+                | WindowEvent::MouseWheel { .. }
+                | WindowEvent::CursorMoved { .. }
+                | WindowEvent::Touch(_)
+        ) {
+            last_activity_input.set(Instant::now());
+        }
+        EventResult::Propagate
+    });
+
+    // Auto-lock after the configured stretch without user activity
+    let auto_lock_timer = slint::Timer::default();
+    let ui_handle_idle = ui.as_weak();
+    auto_lock_timer.start(slint::TimerMode::Repeated, Duration::from_secs(15), move || {
+        let Some(ui) = ui_handle_idle.upgrade() else {
+            return;
+        };
+        let minutes = ui.get_auto_lock_minutes();
+        if ui.get_is_locked() || minutes <= 0 {
+            return;
+        }
+        if last_activity.get().elapsed() >= Duration::from_secs(minutes as u64 * 60) {
+            info!("Locking after {} minutes of inactivity", minutes);
+            lock_app(&ui);
         }
     });
 
-    ui.run()
+    let result = ui.run();
+    stop_mail_watchers();
+    // Don't wait for blocking work such as a folder sync still in progress
+    rt.shutdown_background();
+    result
 }
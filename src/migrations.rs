@@ -0,0 +1,313 @@
+use rusqlite::{ffi, Connection, Result, Transaction};
+use tracing::info;
+
+type Migration = fn(&Transaction) -> Result<()>;
+
+/// Ordered schema migrations; entry `i` upgrades the database to version `i + 1`
+/// (stored in `PRAGMA user_version`). Append new steps, never edit shipped ones.
+const MIGRATIONS: &[Migration] = &[baseline, full_text_search, sortable_columns, labels, saved_searches, embeddings];
+
+/// Upgrades the schema to the latest version, one transaction per step.
+/// Refuses databases written by a newer build rather than guessing at them.
+pub fn run(conn: &mut Connection) -> Result<()> {
+    let current: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    let latest = MIGRATIONS.len() as i64;
+    if current > latest {
+        return Err(rusqlite::Error::SqliteFailure(
+            ffi::Error::new(ffi::SQLITE_CANTOPEN),
+            Some(format!(
+                "neural-mail.db uses schema version {}, but this build only supports up to {}. Please update Neural Mail.",
+                current, latest
+            )),
+        ));
+    }
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(current as usize) {
+        let version = index as i64 + 1;
+        let tx = conn.transaction()?;
+        migration(&tx)?;
+        tx.pragma_update(None, "user_version", version)?;
+        tx.commit()?;
+        info!("Migrated neural-mail.db to schema version {}", version);
+    }
+    Ok(())
+}
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let names = stmt.query_map([], |row| row.get::<_, String>(1))?;
+    for name in names {
+        if name? == column {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<()> {
+    if !has_column(conn, table, column)? {
+        conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), [])?;
+    }
+    Ok(())
+}
+
+/// Version 1: the schema as it stood before versioning. Databases from earlier
+/// builds may be at any intermediate shape, so every change here is conditional.
+fn baseline(tx: &Transaction) -> Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS accounts (
+            email TEXT PRIMARY KEY,
+            imap_host TEXT NOT NULL,
+            imap_port INTEGER NOT NULL,
+            password TEXT,
+            is_demo INTEGER DEFAULT 0,
+            smtp_host TEXT NOT NULL DEFAULT '',
+            smtp_port INTEGER NOT NULL DEFAULT 587,
+            smtp_username TEXT,
+            smtp_password TEXT
+        )",
+        [],
+    )?;
+    // SMTP settings for accounts created before sending was supported
+    add_column_if_missing(tx, "accounts", "smtp_host", "TEXT NOT NULL DEFAULT ''")?;
+    add_column_if_missing(tx, "accounts", "smtp_port", "INTEGER NOT NULL DEFAULT 587")?;
+    add_column_if_missing(tx, "accounts", "smtp_username", "TEXT")?;
+    add_column_if_missing(tx, "accounts", "smtp_password", "TEXT")?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS emails (
+            id INTEGER PRIMARY KEY,
+            subject TEXT NOT NULL,
+            sender TEXT NOT NULL,
+            date_str TEXT NOT NULL,
+            body TEXT NOT NULL,
+            has_attachment INTEGER NOT NULL,
+            category TEXT NOT NULL DEFAULT 'Inbox',
+            mailbox TEXT NOT NULL DEFAULT 'INBOX',
+            uid INTEGER,
+            is_read INTEGER NOT NULL DEFAULT 0,
+            account TEXT NOT NULL DEFAULT ''
+        )",
+        [],
+    )?;
+    add_column_if_missing(tx, "emails", "category", "TEXT NOT NULL DEFAULT 'Inbox'")?;
+    // IMAP identity and flags for incremental sync
+    add_column_if_missing(tx, "emails", "mailbox", "TEXT NOT NULL DEFAULT 'INBOX'")?;
+    add_column_if_missing(tx, "emails", "uid", "INTEGER")?;
+    add_column_if_missing(tx, "emails", "is_read", "INTEGER NOT NULL DEFAULT 0")?;
+    // Scope synced mail by account. Rows synced before multi-account support
+    // belonged to the single configured account, so attribute them to it.
+    if !has_column(tx, "emails", "account")? {
+        tx.execute("ALTER TABLE emails ADD COLUMN account TEXT NOT NULL DEFAULT ''", [])?;
+        tx.execute(
+            "UPDATE emails SET account = COALESCE((SELECT email FROM accounts WHERE is_demo = 0 LIMIT 1), '')
+             WHERE uid IS NOT NULL",
+            [],
+        )?;
+    }
+    tx.execute("DROP INDEX IF EXISTS idx_emails_mailbox_uid", [])?;
+    tx.execute(
+        "CREATE UNIQUE INDEX IF NOT EXISTS idx_emails_account_mailbox_uid ON emails (account, mailbox, uid)",
+        [],
+    )?;
+
+    // Folder lists and sync cursors are caches of server state; older per-mailbox
+    // versions are simply rebuilt by the next sync.
+    if !has_column(tx, "mailboxes", "account")? {
+        tx.execute("DROP TABLE IF EXISTS mailboxes", [])?;
+    }
+    if !has_column(tx, "sync_state", "account")? {
+        tx.execute("DROP TABLE IF EXISTS sync_state", [])?;
+    }
+
+    // IMAP folder hierarchy from LIST
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS mailboxes (
+            account TEXT NOT NULL,
+            name TEXT NOT NULL,
+            delimiter TEXT,
+            special_use TEXT,
+            selectable INTEGER NOT NULL DEFAULT 1,
+            PRIMARY KEY (account, name)
+        )",
+        [],
+    )?;
+
+    // Per-mailbox IMAP sync cursor
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS sync_state (
+            account TEXT NOT NULL,
+            mailbox TEXT NOT NULL,
+            uid_validity INTEGER NOT NULL,
+            highest_uid INTEGER NOT NULL,
+            PRIMARY KEY (account, mailbox)
+        )",
+        [],
+    )?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS settings (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    // Master password hash, key salts and other security state
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS security (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Version 2: recipients and parsed dates for search operators, plus an FTS5
+/// index over subject, sender, recipients and body kept current by triggers.
+fn full_text_search(tx: &Transaction) -> Result<()> {
+    tx.execute("ALTER TABLE emails ADD COLUMN recipients TEXT NOT NULL DEFAULT ''", [])?;
+    tx.execute("ALTER TABLE emails ADD COLUMN received_at INTEGER", [])?;
+    tx.execute("CREATE INDEX idx_emails_received_at ON emails (received_at)", [])?;
+
+    // Synced mail stored the raw Date header; relative demo dates stay NULL
+    let dated: Vec<(i64, String)> = {
+        let mut stmt = tx.prepare("SELECT id, date_str FROM emails WHERE uid IS NOT NULL")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect::<Result<_>>()?
+    };
+    {
+        let mut update = tx.prepare("UPDATE emails SET received_at = ?1 WHERE id = ?2")?;
+        for (id, date) in dated {
+            if let Ok(epoch) = mailparse::dateparse(&date) {
+                update.execute(rusqlite::params![epoch, id])?;
+            }
+        }
+    }
+
+    tx.execute_batch(
+        "CREATE VIRTUAL TABLE emails_fts USING fts5(
+            subject, sender, recipients, body,
+            content='emails', content_rowid='id',
+            tokenize='unicode61 remove_diacritics 2'
+        );
+
+        CREATE TRIGGER emails_fts_insert AFTER INSERT ON emails BEGIN
+            INSERT INTO emails_fts (rowid, subject, sender, recipients, body)
+            VALUES (new.id, new.subject, new.sender, new.recipients, new.body);
+        END;
+
+        CREATE TRIGGER emails_fts_delete AFTER DELETE ON emails BEGIN
+            INSERT INTO emails_fts (emails_fts, rowid, subject, sender, recipients, body)
+            VALUES ('delete', old.id, old.subject, old.sender, old.recipients, old.body);
+        END;
+
+        CREATE TRIGGER emails_fts_update AFTER UPDATE OF subject, sender, recipients, body ON emails BEGIN
+            INSERT INTO emails_fts (emails_fts, rowid, subject, sender, recipients, body)
+            VALUES ('delete', old.id, old.subject, old.sender, old.recipients, old.body);
+            INSERT INTO emails_fts (rowid, subject, sender, recipients, body)
+            VALUES (new.id, new.subject, new.sender, new.recipients, new.body);
+        END;
+
+        INSERT INTO emails_fts (emails_fts) VALUES ('rebuild');",
+    )?;
+    Ok(())
+}
+
+/// Version 3: message sizes for sorting, and timestamps for the demo inbox,
+/// whose dates were stored as "5d ago" text relative to when it was generated.
+fn sortable_columns(tx: &Transaction) -> Result<()> {
+    tx.execute("ALTER TABLE emails ADD COLUMN size INTEGER NOT NULL DEFAULT 0", [])?;
+    // Raw sizes were never stored; the body is the closest approximation
+    tx.execute("UPDATE emails SET size = length(CAST(body AS BLOB))", [])?;
+
+    let relative: Vec<(i64, String)> = {
+        let mut stmt = tx.prepare("SELECT id, date_str FROM emails WHERE received_at IS NULL AND uid IS NULL")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect::<Result<_>>()?
+    };
+    let now = chrono::Utc::now().timestamp();
+    let mut update = tx.prepare("UPDATE emails SET received_at = ?1 WHERE id = ?2")?;
+    for (id, date) in relative {
+        if let Some(seconds_ago) = parse_relative_age(&date) {
+            update.execute(rusqlite::params![now - seconds_ago, id])?;
+        }
+    }
+    Ok(())
+}
+
+/// Seconds represented by "12m ago", "3h ago" or "5d ago".
+fn parse_relative_age(text: &str) -> Option<i64> {
+    let amount = text.strip_suffix(" ago")?;
+    let unit = amount.chars().last()?;
+    let count: i64 = amount[..amount.len() - unit.len_utf8()].parse().ok()?;
+    match unit {
+        'm' => Some(count * 60),
+        'h' => Some(count * 3600),
+        'd' => Some(count * 86400),
+        _ => None,
+    }
+}
+
+/// Version 4: user-defined labels assigned many-to-many, replacing the single
+/// `category` column. Existing categories become labels of the same name.
+fn labels(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE labels (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL UNIQUE COLLATE NOCASE,
+            color TEXT NOT NULL
+        );
+
+        CREATE TABLE email_labels (
+            email_id INTEGER NOT NULL REFERENCES emails (id) ON DELETE CASCADE,
+            label_id INTEGER NOT NULL REFERENCES labels (id) ON DELETE CASCADE,
+            PRIMARY KEY (email_id, label_id)
+        );
+        CREATE INDEX idx_email_labels_label ON email_labels (label_id);
+
+        INSERT INTO labels (name, color) VALUES
+            ('Work', '#0078d4'),
+            ('Finance', '#107c10'),
+            ('Social', '#8764b8'),
+            ('Promotions', '#ca5010');
+        INSERT OR IGNORE INTO labels (name, color)
+            SELECT DISTINCT category, '#038387' FROM emails WHERE category <> 'Inbox';
+        INSERT INTO email_labels (email_id, label_id)
+            SELECT e.id, l.id FROM emails e JOIN labels l ON l.name = e.category;
+
+        -- Set once the AI categorizer has looked at an email
+        ALTER TABLE emails ADD COLUMN categorized INTEGER NOT NULL DEFAULT 0;
+        UPDATE emails SET categorized = 1 WHERE category <> 'Inbox';
+        ALTER TABLE emails DROP COLUMN category;",
+    )
+}
+
+/// Version 5: saved searches, shown as smart folders.
+fn saved_searches(tx: &Transaction) -> Result<()> {
+    tx.execute(
+        "CREATE TABLE saved_searches (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL UNIQUE COLLATE NOCASE,
+            query TEXT NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Version 6: embedding vectors for retrieval in the inbox chat, one per email,
+/// computed in the background. `model` names the embedding model, so a change
+/// of model re-embeds everything.
+fn embeddings(tx: &Transaction) -> Result<()> {
+    tx.execute(
+        "CREATE TABLE email_embeddings (
+            email_id INTEGER PRIMARY KEY REFERENCES emails (id) ON DELETE CASCADE,
+            model TEXT NOT NULL,
+            vector BLOB NOT NULL -- little-endian f32s
+        )",
+        [],
+    )?;
+    Ok(())
+}
@@ -0,0 +1,112 @@
+use std::env;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+const DATA_DIR_ENV: &str = "NEURAL_MAIL_DATA_DIR";
+const PROFILE_ENV: &str = "NEURAL_MAIL_PROFILE";
+pub const DEFAULT_PROFILE: &str = "default";
+const DB_FILE: &str = "neural-mail.db";
+
+/// A named set of data (database and its sidecar files) under the data directory.
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub name: String,
+    pub dir: PathBuf,
+}
+
+impl Profile {
+    pub fn database_path(&self) -> PathBuf {
+        self.dir.join(DB_FILE)
+    }
+}
+
+/// Where profiles live: `--data-dir`, then `NEURAL_MAIL_DATA_DIR`, then the
+/// platform data directory (`$XDG_DATA_HOME/neural-mail` on Linux).
+pub fn data_dir() -> PathBuf {
+    arg_value("--data-dir")
+        .or_else(|| env::var(DATA_DIR_ENV).ok().filter(|v| !v.is_empty()))
+        .map(PathBuf::from)
+        .or_else(|| dirs::data_dir().map(|dir| dir.join("neural-mail")))
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+/// Picks the profile named by `--profile` or `NEURAL_MAIL_PROFILE` (default
+/// "default") and creates its directory.
+pub fn select() -> Result<Profile, String> {
+    let name = arg_value("--profile")
+        .or_else(|| env::var(PROFILE_ENV).ok().filter(|v| !v.is_empty()))
+        .unwrap_or_else(|| DEFAULT_PROFILE.to_string());
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(format!(
+            "Invalid profile name {:?}: use letters, digits, '-' and '_' only",
+            name
+        ));
+    }
+
+    let profile = Profile { dir: data_dir().join(&name), name };
+    std::fs::create_dir_all(&profile.dir)
+        .map_err(|e| format!("Cannot create {}: {}", profile.dir.display(), e))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let _ = std::fs::set_permissions(&profile.dir, std::fs::Permissions::from_mode(0o700));
+    }
+
+    if profile.name == DEFAULT_PROFILE {
+        adopt_legacy_database(&profile);
+    }
+    Ok(profile)
+}
+
+/// Names of the profiles that already exist under the data directory.
+pub fn list() -> Vec<String> {
+    let mut names: Vec<String> = std::fs::read_dir(data_dir())
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.path().join(DB_FILE).exists())
+                .filter_map(|entry| entry.file_name().into_string().ok())
+                .collect()
+        })
+        .unwrap_or_default();
+    names.sort();
+    names
+}
+
+/// Earlier builds kept the database in the working directory. Move it (and its
+/// sidecar files) into the default profile the first time that profile is used.
+fn adopt_legacy_database(profile: &Profile) {
+    let legacy = Path::new(DB_FILE);
+    let target = profile.database_path();
+    if target.exists() || !legacy.is_file() {
+        return;
+    }
+    for suffix in ["", "-wal", "-shm", ".unlock"] {
+        let from = PathBuf::from(format!("{}{}", DB_FILE, suffix));
+        let to = profile.dir.join(format!("{}{}", DB_FILE, suffix));
+        if !from.is_file() {
+            continue;
+        }
+        // rename fails across filesystems; fall back to copy + remove
+        let moved = std::fs::rename(&from, &to)
+            .or_else(|_| std::fs::copy(&from, &to).and_then(|_| std::fs::remove_file(&from)));
+        match moved {
+            Ok(()) => info!("Moved {} to {}", from.display(), to.display()),
+            Err(e) => warn!("Could not move {} to {}: {}", from.display(), to.display(), e),
+        }
+    }
+}
+
+/// Value of `--name value` or `--name=value` on the command line.
+fn arg_value(name: &str) -> Option<String> {
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == name {
+            return args.next();
+        }
+        if let Some(value) = arg.strip_prefix(name).and_then(|rest| rest.strip_prefix('=')) {
+            return Some(value.to_string());
+        }
+    }
+    None
+}
@@ -0,0 +1,243 @@
+use crate::llm::{self, Task};
+use crate::{db, sanitize_for_prompt};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use tracing::{debug, warn};
+
+/// Candidates taken from each of full-text search and the embeddings.
+const CANDIDATES: usize = 30;
+/// Reciprocal rank fusion offset; keeps one list's top hit from outweighing
+/// an email both lists rank well.
+const RRF_K: f32 = 60.0;
+/// Tokens left for the instructions, the question and the answer.
+const RESERVED_TOKENS: usize = 1536;
+/// Context window assumed when the chat task leaves it to the server.
+const DEFAULT_CONTEXT_TOKENS: usize = 4096;
+/// Longest part of one body put in the context, in characters.
+const MAX_BODY_CHARS: usize = 1500;
+/// Emails sent per embedding request while indexing.
+const EMBED_BATCH: usize = 16;
+
+/// Words too common to say what a question is about.
+const STOP_WORDS: &[&str] = &[
+    "about", "all", "and", "any", "are", "can", "could", "did", "does", "email", "emails", "for", "from", "get", "give",
+    "had", "has", "have", "how", "inbox", "into", "list", "mail", "me", "message", "messages", "my", "please", "show",
+    "tell", "that", "the", "their", "them", "there", "they", "this", "was", "were", "what", "when", "where", "which",
+    "who", "why", "with", "would", "you", "your",
+];
+
+/// Embeds up to `limit` emails, newest first, that have no vector from the
+/// configured embedding model yet. Stops at the first failure (e.g. the model
+/// is not installed); chat then falls back to full-text search alone.
+/// Returns how many were embedded.
+pub async fn index_embeddings(limit: usize) -> usize {
+    let model = llm::model_for(Task::Embedding);
+    let mut indexed = 0;
+    while indexed < limit {
+        let batch_model = model.clone();
+        let batch_size = EMBED_BATCH.min(limit - indexed);
+        let batch = match db::call(move || db::get_emails_to_embed(&batch_model, batch_size)).await {
+            Ok(batch) if !batch.is_empty() => batch,
+            Ok(_) => break,
+            Err(e) => {
+                warn!("Failed to read emails to embed: {}", e);
+                break;
+            }
+        };
+        let texts: Vec<String> = batch.iter().map(|(_, text)| text.clone()).collect();
+        let vectors = match llm::embed(&texts).await {
+            Ok(vectors) => vectors,
+            Err(e) => {
+                debug!("Embedding with {} failed, chat will use full-text search only: {}", model, e);
+                break;
+            }
+        };
+        let rows: Vec<(i64, Vec<f32>)> = batch.into_iter().map(|(id, _)| id).zip(vectors).collect();
+        let save_model = model.clone();
+        let count = rows.len();
+        if let Err(e) = db::call(move || db::save_embeddings(&save_model, &rows)).await {
+            warn!("Failed to save embeddings: {}", e);
+            break;
+        }
+        indexed += count;
+    }
+    indexed
+}
+
+/// Builds the emails context for a chat question about `account` (every
+/// account when empty): the best full-text matches and nearest embeddings,
+/// merged by rank, as many as fit the chat task's context window. Falls back
+/// to the newest emails when nothing matches.
+pub async fn context_for_question(question: &str, account: &str) -> String {
+    let mut scores: HashMap<i64, f32> = HashMap::new();
+
+    if let Some(fts_query) = fts_query(question) {
+        let fts_account = account.to_string();
+        match db::call(move || db::search_email_ids(&fts_query, &fts_account, CANDIDATES)).await {
+            Ok(ids) => add_ranks(&mut scores, &ids),
+            Err(e) => warn!("Full-text retrieval failed: {}", e),
+        }
+    }
+    add_ranks(&mut scores, &nearest_by_embedding(question, account).await);
+
+    let mut ranked: Vec<(i64, f32)> = scores.into_iter().collect();
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(b.0.cmp(&a.0)));
+    let ids: Vec<i64> = ranked.into_iter().map(|(id, _)| id).collect();
+
+    let emails = if ids.is_empty() {
+        db::call(|| db::get_recent_emails(CANDIDATES)).await
+    } else {
+        db::call(move || db::get_emails_by_ids(&ids)).await
+    };
+    let emails = match emails {
+        Ok(emails) if !emails.is_empty() => emails,
+        _ => return "No emails found in SQLite database.".to_string(),
+    };
+
+    let context_tokens = llm::task_settings(Task::Chat)
+        .context_tokens
+        .map(|t| t as usize)
+        .unwrap_or(DEFAULT_CONTEXT_TOKENS);
+    // Roughly four characters to a token
+    let mut budget = context_tokens.saturating_sub(RESERVED_TOKENS + question.len() / 4) * 4;
+
+    let mut context = String::new();
+    let mut included = 0;
+    for e in &emails {
+        let header = format!(
+            "Date: {}\nFrom: {}\nSubject: {}\nBody: ",
+            sanitize_for_prompt(&e.date),
+            sanitize_for_prompt(&e.sender),
+            sanitize_for_prompt(&e.subject)
+        );
+        if header.len() + 100 > budget {
+            break;
+        }
+        let body_chars = MAX_BODY_CHARS.min(budget - header.len() - 2);
+        let mut body: String = e.body.chars().take(body_chars).collect();
+        if body.len() < e.body.len() {
+            body.push_str("...");
+        }
+        let block = format!("{}{}\n\n", header, sanitize_for_prompt(&body));
+        budget = budget.saturating_sub(block.len());
+        context.push_str(&block);
+        included += 1;
+    }
+    debug!("Chat context: {} of {} retrieved emails", included, emails.len());
+    context
+}
+
+/// Adds reciprocal-rank scores for `ids`, best first.
+fn add_ranks(scores: &mut HashMap<i64, f32>, ids: &[i64]) {
+    for (rank, id) in ids.iter().enumerate() {
+        *scores.entry(*id).or_default() += 1.0 / (RRF_K + rank as f32 + 1.0);
+    }
+}
+
+/// An FTS5 expression matching any content word of the question, or `None`
+/// if it has none.
+fn fts_query(question: &str) -> Option<String> {
+    let mut terms: Vec<String> = Vec::new();
+    for word in question.split(|c: char| !c.is_alphanumeric()) {
+        let word = word.to_lowercase();
+        if word.chars().count() >= 3 && !STOP_WORDS.contains(&word.as_str()) && !terms.contains(&word) {
+            terms.push(word);
+        }
+    }
+    if terms.is_empty() {
+        return None;
+    }
+    terms.truncate(12);
+    Some(terms.iter().map(|t| format!("\"{}\"", t)).collect::<Vec<_>>().join(" OR "))
+}
+
+/// Ids of the emails of `account` whose embeddings are closest to the
+/// question's, best first; empty if nothing is indexed or the embedding model
+/// is unavailable. The stored vectors are streamed, keeping only the best
+/// `CANDIDATES` seen so far.
+async fn nearest_by_embedding(question: &str, account: &str) -> Vec<i64> {
+    let query = match llm::embed(&[question.to_string()]).await {
+        Ok(mut vectors) => vectors.remove(0),
+        Err(e) => {
+            debug!("Could not embed the question: {}", e);
+            return Vec::new();
+        }
+    };
+    let model = llm::model_for(Task::Embedding);
+    let account = account.to_string();
+    let nearest = db::call(move || {
+        let mut best = BinaryHeap::with_capacity(CANDIDATES + 1);
+        db::scan_embeddings(&model, &account, |id, vector| {
+            best.push(Reverse(Scored(cosine(&query, vector), id)));
+            if best.len() > CANDIDATES {
+                best.pop();
+            }
+        })?;
+        Ok(best.into_sorted_vec().into_iter().map(|Reverse(Scored(_, id))| id).collect())
+    })
+    .await;
+    nearest.unwrap_or_else(|e| {
+        warn!("Embedding retrieval failed: {}", e);
+        Vec::new()
+    })
+}
+
+/// A similarity and the email it belongs to, ordered by similarity.
+struct Scored(f32, i64);
+
+impl PartialEq for Scored {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Scored {}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0).then(other.1.cmp(&self.1))
+    }
+}
+
+fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = a.iter().map(|x| x * x).sum::<f32>().sqrt() * b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm == 0.0 { 0.0 } else { dot / norm }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fts_query_keeps_content_words() {
+        assert_eq!(
+            fts_query("What did Alice say about the budget? The BUDGET!").as_deref(),
+            Some("\"alice\" OR \"say\" OR \"budget\"")
+        );
+        assert_eq!(fts_query("What is in my inbox?"), None);
+    }
+
+    #[tokio::test]
+    async fn fake_embeddings_rank_shared_words_closer() {
+        llm::use_fake_backend();
+        let texts = [
+            "When is the budget review?".to_string(),
+            "Quarterly budget review on Thursday".to_string(),
+            "Photos from our beach holiday".to_string(),
+        ];
+        let vectors = llm::embed(&texts).await.unwrap();
+        assert!((cosine(&vectors[0], &vectors[0]) - 1.0).abs() < 1e-6);
+        assert!(cosine(&vectors[0], &vectors[1]) > cosine(&vectors[0], &vectors[2]));
+        assert_eq!(cosine(&vectors[0], &vectors[1][..10]), 0.0);
+    }
+}
@@ -0,0 +1,289 @@
+use chrono::{NaiveDate, Utc};
+
+/// A search box query split into full-text terms and structured filters.
+///
+/// Supported syntax: bare words (prefix matched), `"quoted phrases"`,
+/// `from:`, `to:`, `subject:`, `has:attachment`, `is:unread`, `is:read`,
+/// `label:` (or `category:`), `before:YYYY-MM-DD`, `after:YYYY-MM-DD`, and
+/// `newer_than:`/`older_than:` with a count of `d`ays, `w`eeks, `m`onths or
+/// `y`ears (e.g. `newer_than:7d`). Operator values may be quoted. A leading
+/// `-` on a word, phrase or `from:`/`to:`/`subject:` term leaves out the mail
+/// it matches.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SearchQuery {
+    /// FTS5 MATCH clauses, already quoted; joined with AND.
+    pub fts_clauses: Vec<String>,
+    /// FTS5 MATCH clauses for negated terms; mail matching any is left out.
+    pub excluded_clauses: Vec<String>,
+    pub has_attachment: bool,
+    /// `Some(false)` for `is:unread`, `Some(true)` for `is:read`.
+    pub read: Option<bool>,
+    /// Label name, matched ignoring case.
+    pub label: Option<String>,
+    /// UTC epoch seconds; `before` is exclusive, `after` inclusive.
+    pub before: Option<i64>,
+    pub after: Option<i64>,
+}
+
+impl SearchQuery {
+    /// The FTS5 MATCH expression, or `None` when only filters were given.
+    pub fn match_expression(&self) -> Option<String> {
+        if self.fts_clauses.is_empty() {
+            None
+        } else {
+            Some(self.fts_clauses.join(" AND "))
+        }
+    }
+
+    /// The FTS5 MATCH expression for the negated terms, or `None` if there are none.
+    pub fn exclude_expression(&self) -> Option<String> {
+        if self.excluded_clauses.is_empty() {
+            None
+        } else {
+            Some(self.excluded_clauses.join(" OR "))
+        }
+    }
+}
+
+pub fn parse(input: &str) -> SearchQuery {
+    let mut query = SearchQuery::default();
+    for token in tokenize(input) {
+        match token {
+            Token::Phrase(text) => query.fts_clauses.push(quote(&text)),
+            Token::Word(word) => {
+                // FTS5 has no unary NOT, so negated terms are matched separately
+                if let Some(clause) = word.strip_prefix('-').and_then(text_clause) {
+                    query.excluded_clauses.push(clause);
+                    continue;
+                }
+                if let Some(clause) = text_clause(&word) {
+                    query.fts_clauses.push(clause);
+                    continue;
+                }
+                // `text_clause` only declines `operator:value` words
+                let (operator, value) = word.split_once(':').unwrap_or_default();
+                let value = value.trim_matches('"');
+                match operator.to_ascii_lowercase().as_str() {
+                    "has" if value.eq_ignore_ascii_case("attachment") => query.has_attachment = true,
+                    "is" if value.eq_ignore_ascii_case("unread") => query.read = Some(false),
+                    "is" if value.eq_ignore_ascii_case("read") => query.read = Some(true),
+                    "label" | "category" => query.label = Some(value.to_string()),
+                    "before" if parse_date(value).is_some() => query.before = parse_date(value),
+                    "after" if parse_date(value).is_some() => query.after = parse_date(value),
+                    // Relative to now, so saved searches stay current
+                    "newer_than" if parse_age(value).is_some() => {
+                        query.after = parse_age(value).map(|age| Utc::now().timestamp() - age)
+                    }
+                    "older_than" if parse_age(value).is_some() => {
+                        query.before = parse_age(value).map(|age| Utc::now().timestamp() - age)
+                    }
+                    // Unknown operators (or bad dates) are searched as plain text
+                    _ => query.fts_clauses.push(quote(&word)),
+                }
+            }
+        }
+    }
+    query
+}
+
+/// The FTS5 clause for a bare word (prefix matched), a `"quoted phrase"` or a
+/// `from:`/`to:`/`subject:` term; `None` for filter operators.
+fn text_clause(word: &str) -> Option<String> {
+    if word.starts_with('"') {
+        // Only after `-`; the tokenizer splits other phrases out
+        let phrase = word.trim_matches('"');
+        return (!phrase.trim().is_empty()).then(|| quote(phrase));
+    }
+    let Some((operator, value)) = word.split_once(':').filter(|(_, v)| !v.is_empty()) else {
+        return (!word.is_empty()).then(|| format!("{}*", quote(word)));
+    };
+    let column = match operator.to_ascii_lowercase().as_str() {
+        "from" => "sender",
+        "to" => "recipients",
+        "subject" => "subject",
+        _ => return None,
+    };
+    Some(format!("{} : {}", column, quote(value.trim_matches('"'))))
+}
+
+enum Token {
+    Word(String),
+    Phrase(String),
+}
+
+/// Splits on whitespace, keeping `"quoted phrases"` (also after `operator:`) together.
+fn tokenize(input: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+    let mut quote_started_token = false;
+
+    for c in input.chars() {
+        match c {
+            '"' if in_quotes => {
+                in_quotes = false;
+                if quote_started_token {
+                    tokens.push(Token::Phrase(std::mem::take(&mut current)));
+                } else {
+                    current.push(c);
+                }
+            }
+            '"' => {
+                in_quotes = true;
+                quote_started_token = current.is_empty();
+                if !quote_started_token {
+                    current.push(c);
+                }
+            }
+            c if c.is_whitespace() && !in_quotes => {
+                if !current.is_empty() {
+                    tokens.push(Token::Word(std::mem::take(&mut current)));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        // An unterminated quote still searches for what was typed
+        tokens.push(if in_quotes && quote_started_token {
+            Token::Phrase(current)
+        } else {
+            Token::Word(current)
+        });
+    }
+    tokens.retain(|t| !matches!(t, Token::Phrase(p) if p.trim().is_empty()));
+    tokens
+}
+
+/// Quotes a string as an FTS5 phrase so punctuation is never read as syntax.
+fn quote(text: &str) -> String {
+    format!("\"{}\"", text.replace('"', "\"\""))
+}
+
+/// `YYYY-MM-DD` (or `YYYY/MM/DD`) as UTC midnight in epoch seconds.
+fn parse_date(value: &str) -> Option<i64> {
+    let value = value.replace('/', "-");
+    NaiveDate::parse_from_str(&value, "%Y-%m-%d")
+        .ok()
+        .and_then(|d| d.and_hms_opt(0, 0, 0))
+        .map(|dt| dt.and_utc().timestamp())
+}
+
+/// `7d`, `2w`, `3m` or `1y` in seconds (months and years approximated).
+fn parse_age(value: &str) -> Option<i64> {
+    let unit = value.chars().last()?;
+    let count: i64 = value[..value.len() - unit.len_utf8()].parse().ok()?;
+    let day = 86_400;
+    let unit_seconds = match unit.to_ascii_lowercase() {
+        'd' => day,
+        'w' => 7 * day,
+        'm' => 30 * day,
+        'y' => 365 * day,
+        _ => return None,
+    };
+    count.checked_mul(unit_seconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::Connection;
+
+    #[test]
+    fn quoted_phrases_match_exactly() {
+        let query = parse("\"quarterly report\" budget");
+        assert_eq!(query.fts_clauses, ["\"quarterly report\"", "\"budget\"*"]);
+        assert_eq!(query.match_expression().as_deref(), Some("\"quarterly report\" AND \"budget\"*"));
+    }
+
+    #[test]
+    fn field_operators_match_their_column() {
+        let query = parse("from:alice@example.com Subject:\"big news\" to:bob");
+        assert_eq!(
+            query.fts_clauses,
+            ["sender : \"alice@example.com\"", "subject : \"big news\"", "recipients : \"bob\""]
+        );
+        // An operator without a value is just a word
+        assert_eq!(parse("from:").fts_clauses, ["\"from:\"*"]);
+    }
+
+    #[test]
+    fn negated_terms_are_excluded() {
+        let query = parse("invoice -draft -\"out of office\" -from:noreply");
+        assert_eq!(query.fts_clauses, ["\"invoice\"*"]);
+        assert_eq!(
+            query.excluded_clauses,
+            ["\"draft\"*", "\"out of office\"", "sender : \"noreply\""]
+        );
+        assert_eq!(
+            query.exclude_expression().as_deref(),
+            Some("\"draft\"* OR \"out of office\" OR sender : \"noreply\"")
+        );
+
+        let only_negated = parse("-spam");
+        assert_eq!(only_negated.match_expression(), None);
+        assert_eq!(only_negated.exclude_expression().as_deref(), Some("\"spam\"*"));
+
+        // Filters can't be negated, and a lone dash is searched as text
+        assert_eq!(parse("-has:attachment").fts_clauses, ["\"-has:attachment\""]);
+        assert_eq!(parse("- x").fts_clauses, ["\"-\"*", "\"x\"*"]);
+    }
+
+    #[test]
+    fn filters_are_not_full_text() {
+        let query = parse("has:attachment is:unread label:Work after:2024/01/31 before:2024-02-01");
+        assert_eq!(query.match_expression(), None);
+        assert!(query.has_attachment);
+        assert_eq!(query.read, Some(false));
+        assert_eq!(query.label.as_deref(), Some("Work"));
+        assert_eq!(query.after, Some(1_706_659_200));
+        assert_eq!(query.before, Some(1_706_745_600));
+        // Bad dates and unknown operators are searched as text
+        assert_eq!(parse("before:soon foo:bar").fts_clauses, ["\"before:soon\"", "\"foo:bar\""]);
+    }
+
+    #[test]
+    fn empty_and_unbalanced_input() {
+        assert_eq!(parse(""), SearchQuery::default());
+        assert_eq!(parse("  \t "), SearchQuery::default());
+        assert_eq!(parse("\"\" \"  \""), SearchQuery::default());
+        assert_eq!(parse("\"unterminated phrase").fts_clauses, ["\"unterminated phrase\""]);
+        assert_eq!(parse("subject:\"half open").fts_clauses, ["subject : \"half open\""]);
+        assert_eq!(parse("a\"b").fts_clauses, ["\"a\"\"b\"*"]);
+        assert_eq!(parse("\"").match_expression(), None);
+        assert_eq!(parse("-\"\"").fts_clauses, ["\"-\"\"\"\"\"*"]);
+    }
+
+    #[test]
+    fn fts_syntax_is_always_quoted() {
+        let db = Connection::open_in_memory().unwrap();
+        db.execute_batch(
+            "CREATE VIRTUAL TABLE emails_fts USING fts5(subject, sender, recipients, body);
+             INSERT INTO emails_fts VALUES ('Re: (draft) NEAR done', 'alice@example.com', 'bob', 'a*b ^c {x} \"quoted\"');",
+        )
+        .unwrap();
+        let inputs = [
+            "AND OR NOT",
+            "NEAR(a b) a+b",
+            "(draft",
+            "a*b ^c {x} col:umn",
+            "subject:\"a\"b\" from:\"",
+            "\"unterminated ) *",
+            "-NOT -(x -\"y",
+            "'single' `tick` \\ / ;",
+            "\"\"\"",
+            "- -- ---",
+        ];
+        for input in inputs {
+            let query = parse(input);
+            for expression in query.match_expression().into_iter().chain(query.exclude_expression()) {
+                let result: rusqlite::Result<i64> = db.query_row(
+                    "SELECT count(*) FROM emails_fts WHERE emails_fts MATCH ?",
+                    [&expression],
+                    |row| row.get(0),
+                );
+                assert!(result.is_ok(), "{input:?} -> {expression:?}: {result:?}");
+            }
+        }
+    }
+}